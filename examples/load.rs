@@ -10,12 +10,10 @@ use futures::compat::Future01CompatExt;
 use rs_queue_processor::config::{Cli, Mode};
 use rs_queue_processor::errors::WorkError;
 use rs_queue_processor::processor::Processor;
-use rs_queue_processor::work::Worker;
+use rs_queue_processor::typed::TypedWorker;
 use rusoto_core::Region;
-use rusoto_sqs::Message as SqsMessage;
 use rusoto_sqs::{SendMessageError, SendMessageRequest, Sqs, SqsClient as RusotoSqsClient};
 use std::default::Default;
-use std::str::FromStr;
 use std::sync::Arc;
 use tokio::{self, main};
 use uuid::Uuid;
@@ -24,7 +22,7 @@ use uuid::Uuid;
 async fn main() {
     env_logger::init();
     let config = Cli::new().build_config().unwrap();
-    let worker = WorkerImpl::default();
+    let worker = TypedWorker::new(handle_workload);
     let (sqs_client, queue_url) = if let Mode::Local(port, queue_url) = &config.mode {
         (build_sqs_client(port.to_owned()), queue_url.clone())
     } else {
@@ -32,8 +30,10 @@ async fn main() {
     };
 
     tokio::spawn(async move {
-        let mut processor = Processor::new(&config, Box::new(worker)).unwrap();
-        processor.process().await;
+        let processor = Processor::new(&config, Box::new(worker)).unwrap();
+        if let Err(e) = processor.process().await {
+            eprintln!("Processor stopped with error: {}", e);
+        }
     });
 
     loop {
@@ -49,37 +49,19 @@ async fn main() {
     }
 }
 
-#[derive(Clone, Default)]
-pub struct WorkerImpl;
-
-impl Worker for WorkerImpl {
-    fn process(&self, m: SqsMessage) -> Result<(), WorkError> {
-        let workload = parse_workload(&m)?;
-        let difference = millis_since_start(&workload.creation);
-        println!(
-            "Message {} took {} millis to process",
-            &workload.message_id, difference
-        );
-        Ok(())
-    }
+fn handle_workload(workload: WorkLoad) -> Result<(), WorkError> {
+    let difference = millis_since_start(&workload.creation);
+    println!(
+        "Message {} took {} millis to process",
+        &workload.message_id, difference
+    );
+    Ok(())
 }
 
 fn millis_since_start(dt: &DateTime<Utc>) -> i64 {
     Utc::now().timestamp_millis() - dt.timestamp_millis()
 }
 
-fn parse_workload(m: &SqsMessage) -> Result<WorkLoad, WorkError> {
-    m.body
-        .to_owned()
-        .ok_or(WorkError::UnRecoverableError(
-            "Message contains no body".to_owned(),
-        ))
-        .and_then(|body| {
-            WorkLoad::from_str(body.as_ref())
-                .map_err(|e| WorkError::UnRecoverableError(format!("Invalid Workload {:?}", e)))
-        })
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkLoad {
     pub message_id: Uuid,
@@ -95,15 +77,6 @@ impl Default for WorkLoad {
     }
 }
 
-impl FromStr for WorkLoad {
-    type Err = WorkError;
-
-    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
-        serde_json::from_str(s)
-            .map_err(|e| WorkError::UnRecoverableError(format!("Json Error occurred: {:#?}", e)))
-    }
-}
-
 fn build_local_region(port: u32) -> Region {
     Region::Custom {
         name: "local".to_owned(),
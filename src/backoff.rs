@@ -0,0 +1,117 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Bounds of an exponential back-off
+#[derive(Debug, PartialEq, Clone)]
+pub struct BackoffConfig {
+    /// The delay after the first failure
+    pub initial: Duration,
+    /// The longest delay, however many failures happened in a row
+    pub max: Duration,
+    /// How much the delay grows with each failure in a row
+    pub multiplier: u32,
+}
+
+impl BackoffConfig {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        BackoffConfig {
+            initial,
+            max,
+            multiplier: 2,
+        }
+    }
+
+    pub fn with_multiplier(self, multiplier: u32) -> Self {
+        BackoffConfig { multiplier, ..self }
+    }
+}
+
+/// Tracks consecutive failures and computes how long to wait before trying again
+///
+/// Each delay is picked at random between half and all of the exponential delay, so
+/// processors that failed at the same time do not all retry at the same time.
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    config: BackoffConfig,
+    failures: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: BackoffConfig) -> Self {
+        Backoff {
+            config,
+            failures: 0,
+        }
+    }
+
+    /// Records a failure and returns the delay to wait before trying again
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.exponential_delay();
+        self.failures = self.failures.saturating_add(1);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    fn exponential_delay(&self) -> Duration {
+        self.config
+            .multiplier
+            .checked_pow(self.failures)
+            .and_then(|factor| self.config.initial.checked_mul(factor))
+            .unwrap_or(self.config.max)
+            .min(self.config.max)
+    }
+}
+
+/// Asserts that a jittered delay is between min and max milliseconds
+#[cfg(test)]
+pub(crate) fn assert_between(delay: Duration, min: u64, max: u64) {
+    assert!(
+        delay >= Duration::from_millis(min) && delay <= Duration::from_millis(max),
+        "{:?} not between {}ms and {}ms",
+        delay,
+        min,
+        max
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        Backoff::new(BackoffConfig::new(
+            Duration::from_millis(100),
+            Duration::from_millis(1000),
+        ))
+    }
+
+    #[test]
+    fn test_exponential_growth() {
+        let mut backoff = backoff();
+        assert_between(backoff.next_delay(), 50, 100);
+        assert_between(backoff.next_delay(), 100, 200);
+        assert_between(backoff.next_delay(), 200, 400);
+    }
+
+    #[test]
+    fn test_max_delay() {
+        let mut backoff = backoff();
+        for _ in 0..64 {
+            backoff.next_delay();
+        }
+        assert_between(backoff.next_delay(), 500, 1000);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = backoff();
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_between(backoff.next_delay(), 50, 100);
+    }
+}
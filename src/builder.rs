@@ -0,0 +1,446 @@
+use crate::backoff::BackoffConfig;
+use crate::circuit::{CircuitBreakerConfig, CircuitState};
+use crate::config::{Config, Mode, QueueURL, ReceiveSettings, RuntimeConfig, VisibilityHeartbeat};
+use crate::disposition::Disposition;
+use crate::errors::ProcessorError;
+use crate::pool::{BlockingPool, BlockingPoolConfig, BlockingWorker};
+use crate::prefetch::PrefetchConfig;
+use crate::priority::Priority;
+use crate::processor::{
+    build_sqs_client, Hooks, Processor, QueueConsumer, ShareableWorker, WorkerKind,
+};
+use crate::ratelimit::RateLimitConfig;
+use crate::sqs::SqsClient;
+use crate::work::{AsyncWorker, BatchWorker, Worker};
+use rusoto_sqs::SqsClient as RusotoSqsClient;
+use std::sync::Arc;
+use std::time::Duration;
+
+type BlockingWorkerFactory = Box<dyn FnOnce(BlockingPool) -> Arc<ShareableWorker> + Send>;
+
+/// The worker of the processor being built
+enum BuilderWorker {
+    Kind(WorkerKind),
+    /// Sync worker that is run on a blocking pool created at build time
+    Blocking(BlockingWorkerFactory),
+}
+
+/// A queue consumed in addition to the queue of the Config
+struct AdditionalQueue {
+    mode: Mode,
+    receive: ReceiveSettings,
+    worker: WorkerKind,
+}
+
+/// Builds a Processor, starting from the settings of a Config
+///
+/// The settings are validated when the processor is built.
+///
+/// let processor = ProcessorBuilder::new(config)
+///     .worker(WorkerImpl::default())
+///     .max_in_flight(32)
+///     .wait_time(Duration::from_secs(10))
+///     .dead_letter_queue("https://sqs.us-west-2.amazonaws.com/1234/dead-letters")
+///     .on_shutdown(|| println!("Processor stopped"))
+///     .build()?;
+///
+/// Additional queues are consumed alongside the queue of the Config, sharing its in flight
+/// limit and shutdown:
+///
+/// let processor = ProcessorBuilder::new(config)
+///     .worker(OrderWorker::default())
+///     .queue(refunds_mode, ReceiveSettings::default(), RefundWorker::default())
+///     .build()?;
+pub struct ProcessorBuilder {
+    config: Config,
+    worker: Option<BuilderWorker>,
+    queues: Vec<AdditionalQueue>,
+    sqs_client: Option<RusotoSqsClient>,
+    hooks: Hooks,
+}
+
+impl ProcessorBuilder {
+    pub fn new(config: Config) -> Self {
+        ProcessorBuilder {
+            config,
+            worker: None,
+            queues: vec![],
+            sqs_client: None,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Messages are handed to the worker one at a time
+    pub fn worker<W>(self, worker: W) -> Self
+    where
+        W: AsyncWorker + Send + Sync + 'static,
+    {
+        self.with_worker(BuilderWorker::Kind(WorkerKind::Single(Arc::new(worker))))
+    }
+
+    /// Each received batch of messages is handed to the worker
+    pub fn batch_worker<W>(self, worker: W) -> Self
+    where
+        W: BatchWorker + Send + Sync + 'static,
+    {
+        self.with_worker(BuilderWorker::Kind(WorkerKind::Batch(Arc::new(worker))))
+    }
+
+    /// The sync worker is run on a dedicated thread pool sized by blocking_pool
+    pub fn blocking_worker<W>(self, worker: W) -> Self
+    where
+        W: Worker + Send + Sync + 'static,
+    {
+        let factory: BlockingWorkerFactory =
+            Box::new(move |pool| Arc::new(BlockingWorker::new(worker, pool)));
+        self.with_worker(BuilderWorker::Blocking(factory))
+    }
+
+    fn with_worker(self, worker: BuilderWorker) -> Self {
+        ProcessorBuilder {
+            worker: Some(worker),
+            ..self
+        }
+    }
+
+    /// Also consumes the queue of the mode, handing its messages to the worker
+    pub fn queue<W>(self, mode: Mode, receive: ReceiveSettings, worker: W) -> Self
+    where
+        W: AsyncWorker + Send + Sync + 'static,
+    {
+        self.with_queue(mode, receive, WorkerKind::Single(Arc::new(worker)))
+    }
+
+    /// Also consumes the queue of the mode, handing each received batch to the worker
+    pub fn batch_queue<W>(self, mode: Mode, receive: ReceiveSettings, worker: W) -> Self
+    where
+        W: BatchWorker + Send + Sync + 'static,
+    {
+        self.with_queue(mode, receive, WorkerKind::Batch(Arc::new(worker)))
+    }
+
+    fn with_queue(mut self, mode: Mode, receive: ReceiveSettings, worker: WorkerKind) -> Self {
+        self.queues.push(AdditionalQueue {
+            mode,
+            receive,
+            worker,
+        });
+        self
+    }
+
+    /// How capacity is divided between the queues
+    pub fn priority(self, priority: Priority) -> Self {
+        self.map_config(|config| config.with_priority(priority))
+    }
+
+    /// The longest a queue goes without being received from when consumed by priority
+    pub fn starvation_timeout(self, starvation_timeout: Duration) -> Self {
+        self.map_config(|config| config.with_starvation_timeout(starvation_timeout))
+    }
+
+    /// Limits how fast messages are received and handed to workers
+    pub fn rate_limit(self, rate_limit: RateLimitConfig) -> Self {
+        self.map_config(|config| config.with_rate_limit(rate_limit))
+    }
+
+    /// Stops receiving messages while most of them fail
+    pub fn circuit_breaker(self, circuit_breaker: CircuitBreakerConfig) -> Self {
+        self.map_config(|config| config.with_circuit_breaker(circuit_breaker))
+    }
+
+    /// Receives messages into a bounded buffer ahead of processing them
+    pub fn prefetch(self, prefetch: PrefetchConfig) -> Self {
+        self.map_config(|config| config.with_prefetch(prefetch))
+    }
+
+    /// The queue the worker set with worker, batch_worker or blocking_worker consumes
+    pub fn mode(self, mode: Mode) -> Self {
+        self.map_config(|config| config.with_mode(mode))
+    }
+
+    /// Uses an already configured rusoto client for every queue instead of creating one from
+    /// the mode. Messages are still received from the queue of the mode
+    pub fn sqs_client(self, sqs_client: RusotoSqsClient) -> Self {
+        ProcessorBuilder {
+            sqs_client: Some(sqs_client),
+            ..self
+        }
+    }
+
+    /// Maximum number of messages processed at the same time
+    pub fn max_in_flight(self, max_in_flight: usize) -> Self {
+        self.map_config(|config| config.with_max_in_flight(max_in_flight))
+    }
+
+    /// The tokio runtime created by Processor::run
+    pub fn runtime(self, runtime: RuntimeConfig) -> Self {
+        self.map_config(|config| config.with_runtime(runtime))
+    }
+
+    pub fn blocking_pool(self, blocking_pool: BlockingPoolConfig) -> Self {
+        self.map_config(|config| config.with_blocking_pool(blocking_pool))
+    }
+
+    pub fn receive(self, receive: ReceiveSettings) -> Self {
+        self.map_config(|config| config.with_receive(receive))
+    }
+
+    /// Long polls the queue, waiting up to wait_time for messages
+    pub fn wait_time(self, wait_time: Duration) -> Self {
+        self.map_receive(|receive| ReceiveSettings {
+            wait_time: Some(wait_time),
+            ..receive
+        })
+    }
+
+    /// Short polls the queue every poll_interval
+    pub fn poll_interval(self, poll_interval: Duration) -> Self {
+        self.map_receive(|receive| ReceiveSettings {
+            wait_time: None,
+            poll_interval,
+            ..receive
+        })
+    }
+
+    /// The maximum number of messages per receive
+    pub fn max_messages(self, max_messages: usize) -> Self {
+        self.map_receive(|receive| receive.with_max_messages(max_messages))
+    }
+
+    /// The visibility timeout of received messages
+    pub fn visibility_timeout(self, visibility_timeout: Duration) -> Self {
+        self.map_receive(|receive| receive.with_visibility_timeout(visibility_timeout))
+    }
+
+    pub fn visibility_heartbeat(self, visibility_heartbeat: VisibilityHeartbeat) -> Self {
+        self.map_config(|config| config.with_visibility_heartbeat(visibility_heartbeat))
+    }
+
+    pub fn error_backoff(self, error_backoff: BackoffConfig) -> Self {
+        self.map_config(|config| config.with_error_backoff(error_backoff))
+    }
+
+    pub fn empty_backoff(self, empty_backoff: Option<BackoffConfig>) -> Self {
+        self.map_config(|config| config.with_empty_backoff(empty_backoff))
+    }
+
+    /// Queue that dead lettered messages are sent to
+    pub fn dead_letter_queue(self, dead_letter_queue: &str) -> Self {
+        let dead_letter_queue: QueueURL = dead_letter_queue.to_owned();
+        self.map_config(|config| config.with_dead_letter_queue(dead_letter_queue))
+    }
+
+    /// How long a message retried with Disposition::Requeue or a RecoverableError stays hidden
+    pub fn retry_delay(self, retry_delay: Duration) -> Self {
+        self.map_config(|config| config.with_retry_delay(retry_delay))
+    }
+
+    /// What happens to a message when the worker panics while processing it
+    pub fn panic_disposition(self, panic_disposition: Disposition) -> Self {
+        self.map_config(|config| config.with_panic_disposition(panic_disposition))
+    }
+
+    /// What happens to a message when the worker times out
+    pub fn timeout_disposition(self, timeout_disposition: Disposition) -> Self {
+        self.map_config(|config| config.with_timeout_disposition(timeout_disposition))
+    }
+
+    /// Maximum time a worker may spend on one message
+    pub fn message_timeout(self, message_timeout: Duration) -> Self {
+        self.map_config(|config| config.with_message_timeout(message_timeout))
+    }
+
+    /// How long to wait for in flight messages to finish after shutdown is requested
+    pub fn shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        self.map_config(|config| config.with_shutdown_timeout(shutdown_timeout))
+    }
+
+    /// Shuts the processor down when the process receives SIGTERM or SIGINT. Processor::run
+    /// always does
+    pub fn shutdown_on_signal(self, shutdown_on_signal: bool) -> Self {
+        self.map_config(|config| config.with_shutdown_on_signal(shutdown_on_signal))
+    }
+
+    /// Called once the worker has started
+    pub fn on_start<F>(mut self, f: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_start.push(Arc::new(f));
+        self
+    }
+
+    /// Called when the queue becomes empty
+    pub fn on_idle<F>(mut self, f: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_idle.push(Arc::new(f));
+        self
+    }
+
+    /// Called once the processor has stopped
+    pub fn on_shutdown<F>(mut self, f: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_shutdown.push(Arc::new(f));
+        self
+    }
+
+    /// Called with the new state whenever the circuit breaker opens, half opens or closes
+    pub fn on_circuit_change<F>(mut self, f: F) -> Self
+    where
+        F: Fn(CircuitState) + Send + Sync + 'static,
+    {
+        self.hooks.on_circuit_change.push(Arc::new(f));
+        self
+    }
+
+    fn map_config<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Config) -> Config,
+    {
+        ProcessorBuilder {
+            config: f(self.config),
+            ..self
+        }
+    }
+
+    fn map_receive<F>(self, f: F) -> Self
+    where
+        F: FnOnce(ReceiveSettings) -> ReceiveSettings,
+    {
+        self.map_config(|config| {
+            let receive = f(config.receive.clone());
+            config.with_receive(receive)
+        })
+    }
+
+    /// Validates the settings and creates the processor
+    pub fn build(self) -> Result<Processor, ProcessorError> {
+        self.config.validate()?;
+        for queue in &self.queues {
+            queue.receive.validate()?;
+            self.config.validate_prefetch(&queue.receive)?;
+        }
+        if let Priority::Weighted(weights) = &self.config.priority {
+            if weights.len() != self.queues.len() + 1 {
+                return Err(ProcessorError::InvalidConfig(
+                    "priority must have one weight per queue".to_owned(),
+                ));
+            }
+        }
+        let sqs_client = &self.sqs_client;
+        let client_for = |mode: &Mode| match sqs_client {
+            Some(sqs_client) => SqsClient::with_client(sqs_client.clone(), queue_url(mode)),
+            None => build_sqs_client(mode),
+        };
+
+        let mut blocking_pool = None;
+        let worker = match self.worker {
+            Some(BuilderWorker::Kind(worker)) => worker,
+            Some(BuilderWorker::Blocking(factory)) => {
+                let pool = BlockingPool::new(self.config.blocking_pool.clone())?;
+                let worker = WorkerKind::Single(factory(pool.clone()));
+                blocking_pool = Some(pool);
+                worker
+            }
+            None => {
+                return Err(ProcessorError::InvalidConfig(
+                    "a worker must be set".to_owned(),
+                ))
+            }
+        };
+
+        let mut queues = vec![QueueConsumer {
+            sqs_client: client_for(&self.config.mode),
+            receive: self.config.receive.clone(),
+            worker,
+        }];
+        queues.extend(self.queues.into_iter().map(|queue| QueueConsumer {
+            sqs_client: client_for(&queue.mode),
+            receive: queue.receive,
+            worker: queue.worker,
+        }));
+
+        let processor = Processor::from_parts(&self.config, queues, self.hooks);
+        Ok(match blocking_pool {
+            Some(pool) => processor.with_blocking_pool(pool),
+            None => processor,
+        })
+    }
+}
+
+fn queue_url(mode: &Mode) -> &str {
+    match mode {
+        Mode::AWS(_, queue) => queue,
+        Mode::Local(_, queue) => queue,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work::worker_fn;
+    use rusoto_sqs::Message as SqsMessage;
+
+    fn builder() -> ProcessorBuilder {
+        ProcessorBuilder::new(Config::default())
+    }
+
+    #[test]
+    fn test_requires_worker() {
+        assert!(builder().build().is_err());
+    }
+
+    #[test]
+    fn test_validates_settings() {
+        let worker = worker_fn(|_message: SqsMessage| Disposition::Ack);
+        assert!(builder().worker(worker).max_messages(20).build().is_err());
+    }
+
+    #[test]
+    fn test_validates_queue_settings() {
+        let mode = Mode::Local(9324, "other-queue".to_owned());
+        let receive = ReceiveSettings::default().with_max_messages(0);
+        let processor = builder()
+            .worker(worker_fn(|_message: SqsMessage| Disposition::Ack))
+            .queue(
+                mode,
+                receive,
+                worker_fn(|_message: SqsMessage| Disposition::Ack),
+            )
+            .build();
+        assert!(processor.is_err());
+    }
+
+    #[test]
+    fn test_validates_queue_prefetch() {
+        let receive = ReceiveSettings::default().with_visibility_timeout(Duration::from_secs(30));
+        let processor = builder()
+            .receive(receive)
+            .prefetch(PrefetchConfig::new(10))
+            .worker(worker_fn(|_message: SqsMessage| Disposition::Ack))
+            .queue(
+                Mode::Local(9324, "other-queue".to_owned()),
+                ReceiveSettings::default(),
+                worker_fn(|_message: SqsMessage| Disposition::Ack),
+            )
+            .build();
+        assert!(processor.is_err());
+    }
+
+    #[test]
+    fn test_build() {
+        let worker = worker_fn(|_message: SqsMessage| Disposition::Ack);
+        let processor = builder()
+            .blocking_worker(worker)
+            .max_in_flight(8)
+            .poll_interval(Duration::from_millis(500))
+            .on_start(|| ())
+            .build()
+            .unwrap();
+        assert!(processor.stats().blocking_pool.is_some());
+    }
+}
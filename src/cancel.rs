@@ -0,0 +1,122 @@
+use futures::channel::oneshot;
+use futures::future::{self, FutureExt, Shared};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A token that can be used to signal that work should stop
+///
+/// Clones of a token share its state. A child token is cancelled when either it or
+/// any of its ancestors is cancelled, while cancelling a child leaves its parent untouched.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+    ancestors: Vec<Arc<Inner>>,
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    receiver: Shared<oneshot::Receiver<()>>,
+}
+
+impl Inner {
+    fn new() -> Self {
+        let (sender, receiver) = oneshot::channel();
+        Inner {
+            cancelled: AtomicBool::new(false),
+            sender: Mutex::new(Some(sender)),
+            receiver: receiver.shared(),
+        }
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            inner: Arc::new(Inner::new()),
+            ancestors: vec![],
+        }
+    }
+
+    /// Creates a token that is cancelled when this token is cancelled
+    pub fn child_token(&self) -> Self {
+        let mut ancestors = self.ancestors.clone();
+        ancestors.push(Arc::clone(&self.inner));
+        CancellationToken {
+            inner: Arc::new(Inner::new()),
+            ancestors,
+        }
+    }
+
+    /// Cancels this token and all of its children
+    pub fn cancel(&self) {
+        self.inner.cancel()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.chain()
+            .any(|inner| inner.cancelled.load(Ordering::SeqCst))
+    }
+
+    /// Returns a future that completes once the token has been cancelled
+    pub async fn cancelled(&self) {
+        let receivers = self
+            .chain()
+            .map(|inner| inner.receiver.clone())
+            .collect::<Vec<_>>();
+        let _ = future::select_all(receivers).await;
+    }
+
+    fn chain(&self) -> impl Iterator<Item = &Arc<Inner>> {
+        self.ancestors.iter().chain(std::iter::once(&self.inner))
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+        block_on(clone.cancelled());
+    }
+
+    #[test]
+    fn test_parent_cancels_child() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+        parent.cancel();
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        block_on(grandchild.cancelled());
+    }
+
+    #[test]
+    fn test_child_does_not_cancel_parent() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+    }
+}
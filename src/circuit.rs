@@ -0,0 +1,323 @@
+use rusoto_sqs::Message as SqsMessage;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a receive waits while the trial message of a half open circuit is processed
+const TRIAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Settings for the circuit breaker that stops receiving messages while most of them fail
+#[derive(Debug, PartialEq, Clone)]
+pub struct CircuitBreakerConfig {
+    /// The fraction of failed messages, between 0 and 1, that opens the circuit
+    pub failure_rate: f64,
+    /// The number of most recent messages the failure rate is computed over
+    pub window: usize,
+    /// The number of messages that have to be processed before the circuit can open
+    pub minimum_messages: usize,
+    /// How long the circuit stays open before a trial message is let through
+    pub open_duration: Duration,
+}
+
+impl CircuitBreakerConfig {
+    pub fn new(failure_rate: f64, open_duration: Duration) -> Self {
+        CircuitBreakerConfig {
+            failure_rate,
+            open_duration,
+            ..CircuitBreakerConfig::default()
+        }
+    }
+
+    pub fn with_window(self, window: usize, minimum_messages: usize) -> Self {
+        CircuitBreakerConfig {
+            window,
+            minimum_messages,
+            ..self
+        }
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.failure_rate > 0.0
+            && self.failure_rate <= 1.0
+            && self.minimum_messages > 0
+            && self.minimum_messages <= self.window
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_rate: 0.5,
+            window: 20,
+            minimum_messages: 10,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    /// Messages are received as usual
+    Closed,
+    /// No messages are received
+    Open,
+    /// A single trial message is received, its outcome closes or reopens the circuit
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    /// true for each failed message, most recent last
+    outcomes: VecDeque<bool>,
+    opened_at: Instant,
+    /// Whether a trial message is being received or processed while half open
+    trial_in_flight: bool,
+    /// The message id of the trial message once it was received
+    trial: Option<String>,
+}
+
+/// Tracks message outcomes and decides whether messages may be received
+///
+/// Methods that change the state return the new state, so the caller can report it.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: CircuitBreakerConfig, now: Instant) -> Self {
+        CircuitBreaker {
+            config,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: now,
+                trial_in_flight: false,
+                trial: None,
+            }),
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// The number of messages that may be received, or how long to wait before asking again.
+    /// Also returns the new state if the circuit became half open
+    pub(crate) fn allow_receive(
+        &self,
+        now: Instant,
+    ) -> (Result<usize, Duration>, Option<CircuitState>) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => (Ok(usize::max_value()), None),
+            CircuitState::Open => {
+                let reopen_at = inner.opened_at + self.config.open_duration;
+                if now >= reopen_at {
+                    inner.state = CircuitState::HalfOpen;
+                    inner.trial_in_flight = true;
+                    (Ok(1), Some(CircuitState::HalfOpen))
+                } else {
+                    (Err(reopen_at - now), None)
+                }
+            }
+            CircuitState::HalfOpen if inner.trial_in_flight => (Err(TRIAL_POLL_INTERVAL), None),
+            CircuitState::HalfOpen => {
+                inner.trial_in_flight = true;
+                (Ok(1), None)
+            }
+        }
+    }
+
+    /// Remembers the trial message of a half open receive, or lets another trial message
+    /// through if the receive came back empty
+    pub(crate) fn received(&self, messages: &[SqsMessage]) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != CircuitState::HalfOpen || !inner.trial_in_flight || inner.trial.is_some()
+        {
+            return;
+        }
+        match messages.first() {
+            Some(message) => inner.trial = message.message_id.clone(),
+            None => inner.trial_in_flight = false,
+        }
+    }
+
+    /// Lets another trial message through if the message was the trial and is handed back to
+    /// the queue without being processed
+    pub(crate) fn released(&self, message: &SqsMessage) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::HalfOpen && is_trial(&inner, message) {
+            inner.trial_in_flight = false;
+            inner.trial = None;
+        }
+    }
+
+    /// Records the outcome of a processed message and returns the new state if it changed.
+    /// While half open only the outcome of the trial message counts
+    pub(crate) fn record(
+        &self,
+        message: &SqsMessage,
+        failed: bool,
+        now: Instant,
+    ) -> Option<CircuitState> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            // messages received before the circuit opened do not count
+            CircuitState::HalfOpen if !is_trial(&inner, message) => None,
+            CircuitState::HalfOpen if failed => Some(open(&mut inner, now)),
+            CircuitState::HalfOpen => {
+                inner.state = CircuitState::Closed;
+                inner.trial_in_flight = false;
+                inner.trial = None;
+                inner.outcomes.clear();
+                Some(CircuitState::Closed)
+            }
+            CircuitState::Closed => {
+                inner.outcomes.push_back(failed);
+                while inner.outcomes.len() > self.config.window {
+                    inner.outcomes.pop_front();
+                }
+                let processed = inner.outcomes.len();
+                let failures = inner.outcomes.iter().filter(|&&failed| failed).count();
+                if processed >= self.config.minimum_messages
+                    && failures as f64 / processed as f64 >= self.config.failure_rate
+                {
+                    Some(open(&mut inner, now))
+                } else {
+                    None
+                }
+            }
+            // messages received before the circuit opened do not count
+            CircuitState::Open => None,
+        }
+    }
+}
+
+fn is_trial(inner: &Inner, message: &SqsMessage) -> bool {
+    inner.trial_in_flight && inner.trial.is_some() && inner.trial == message.message_id
+}
+
+fn open(inner: &mut Inner, now: Instant) -> CircuitState {
+    inner.state = CircuitState::Open;
+    inner.opened_at = now;
+    inner.trial_in_flight = false;
+    inner.trial = None;
+    inner.outcomes.clear();
+    CircuitState::Open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(now: Instant) -> CircuitBreaker {
+        CircuitBreaker::new(
+            CircuitBreakerConfig::new(0.5, Duration::from_secs(30)).with_window(4, 4),
+            now,
+        )
+    }
+
+    fn message(id: &str) -> SqsMessage {
+        SqsMessage {
+            message_id: Some(id.to_owned()),
+            ..SqsMessage::default()
+        }
+    }
+
+    /// Opens the breaker and returns when it becomes half open
+    fn open_breaker(breaker: &CircuitBreaker, now: Instant) -> Instant {
+        for _ in 0..4 {
+            breaker.record(&message("failed"), true, now);
+        }
+        now + Duration::from_secs(30)
+    }
+
+    #[test]
+    fn test_opens_on_failure_rate() {
+        let now = Instant::now();
+        let breaker = breaker(now);
+        let m = message("1");
+        assert_eq!(None, breaker.record(&m, true, now));
+        assert_eq!(None, breaker.record(&m, false, now));
+        assert_eq!(None, breaker.record(&m, false, now));
+        assert_eq!(Some(CircuitState::Open), breaker.record(&m, true, now));
+        assert_eq!(
+            (Err(Duration::from_secs(30)), None),
+            breaker.allow_receive(now)
+        );
+    }
+
+    #[test]
+    fn test_half_open_trial() {
+        let now = Instant::now();
+        let breaker = breaker(now);
+        let later = open_breaker(&breaker, now);
+        assert_eq!(
+            (Ok(1), Some(CircuitState::HalfOpen)),
+            breaker.allow_receive(later)
+        );
+        assert_eq!(
+            (Err(TRIAL_POLL_INTERVAL), None),
+            breaker.allow_receive(later)
+        );
+        let trial = message("trial");
+        breaker.received(&[trial.clone()]);
+        assert_eq!(
+            Some(CircuitState::Open),
+            breaker.record(&trial, true, later)
+        );
+
+        let even_later = later + Duration::from_secs(30);
+        assert_eq!(Ok(1), breaker.allow_receive(even_later).0);
+        breaker.received(&[trial.clone()]);
+        assert_eq!(
+            Some(CircuitState::Closed),
+            breaker.record(&trial, false, even_later)
+        );
+        assert_eq!(Ok(usize::max_value()), breaker.allow_receive(even_later).0);
+    }
+
+    #[test]
+    fn test_empty_trial_receive() {
+        let now = Instant::now();
+        let breaker = breaker(now);
+        let later = open_breaker(&breaker, now);
+        assert_eq!(Ok(1), breaker.allow_receive(later).0);
+        breaker.received(&[]);
+        assert_eq!(Ok(1), breaker.allow_receive(later).0);
+    }
+
+    #[test]
+    fn test_released_trial() {
+        let now = Instant::now();
+        let breaker = breaker(now);
+        let later = open_breaker(&breaker, now);
+        assert_eq!(Ok(1), breaker.allow_receive(later).0);
+        let trial = message("trial");
+        breaker.received(&[trial.clone()]);
+        breaker.released(&message("other"));
+        assert_eq!(Err(TRIAL_POLL_INTERVAL), breaker.allow_receive(later).0);
+        breaker.released(&trial);
+        assert_eq!(Ok(1), breaker.allow_receive(later).0);
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+    }
+
+    #[test]
+    fn test_stale_outcome_while_half_open() {
+        let now = Instant::now();
+        let breaker = breaker(now);
+        let later = open_breaker(&breaker, now);
+        assert_eq!(Ok(1), breaker.allow_receive(later).0);
+        let trial = message("trial");
+        breaker.received(&[trial.clone()]);
+        assert_eq!(None, breaker.record(&message("stale"), false, later));
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+        assert_eq!(
+            Some(CircuitState::Open),
+            breaker.record(&trial, true, later)
+        );
+    }
+}
@@ -0,0 +1,18 @@
+use crate::errors::CodecError;
+use serde::de::DeserializeOwned;
+
+/// Trait to implement to decode a message body into a typed payload
+pub trait Codec<T> {
+    /// Decodes the body of an sqs message
+    fn decode(&self, body: &str) -> Result<T, CodecError>;
+}
+
+/// Codec that decodes message bodies as json. This is the default codec for TypedWorker
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl<T: DeserializeOwned> Codec<T> for JsonCodec {
+    fn decode(&self, body: &str) -> Result<T, CodecError> {
+        serde_json::from_str(body).map_err(|e| CodecError::InvalidBody(e.to_string()))
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
+
+/// In flight slots whose number can be changed while the processor is running
+///
+/// Lowering the limit takes away free slots right away. Slots that are in use when the limit
+/// is lowered are taken away when they are next acquired.
+#[derive(Debug)]
+pub(crate) struct Concurrency {
+    semaphore: Semaphore,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    /// Slots still to be taken away after the limit was lowered
+    excess: usize,
+}
+
+impl Concurrency {
+    pub(crate) fn new(limit: usize) -> Self {
+        Concurrency {
+            semaphore: Semaphore::new(limit),
+            state: Mutex::new(State { limit, excess: 0 }),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// The number of free slots
+    pub(crate) fn available(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        self.semaphore
+            .available_permits()
+            .saturating_sub(state.excess)
+    }
+
+    /// Waits for a free slot and takes it
+    pub(crate) async fn acquire(&self) {
+        loop {
+            self.semaphore.acquire().await.forget();
+            let mut state = self.state.lock().unwrap();
+            if state.excess == 0 {
+                return;
+            }
+            state.excess -= 1;
+        }
+    }
+
+    pub(crate) fn release(&self) {
+        self.semaphore.add_permits(1)
+    }
+
+    pub(crate) fn set_limit(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        if limit > state.limit {
+            let added = limit - state.limit;
+            let restored = added.min(state.excess);
+            state.excess -= restored;
+            self.semaphore.add_permits(added - restored);
+        } else {
+            let mut removed = state.limit - limit;
+            while removed > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => {
+                        permit.forget();
+                        removed -= 1;
+                    }
+                    Err(_) => break,
+                }
+            }
+            state.excess += removed;
+        }
+        state.limit = limit;
+    }
+
+    /// Waits until no slot is in use
+    pub(crate) async fn wait_idle(&self) {
+        let mut held = 0;
+        loop {
+            let total = {
+                let state = self.state.lock().unwrap();
+                state.limit + state.excess
+            };
+            if held >= total {
+                break;
+            }
+            self.semaphore.acquire().await.forget();
+            held += 1;
+        }
+        self.semaphore.add_permits(held);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_raise_limit() {
+        let concurrency = Concurrency::new(1);
+        block_on(concurrency.acquire());
+        assert_eq!(0, concurrency.available());
+        concurrency.set_limit(3);
+        assert_eq!(2, concurrency.available());
+        assert_eq!(3, concurrency.limit());
+    }
+
+    #[test]
+    fn test_lower_limit_while_in_use() {
+        let concurrency = Concurrency::new(3);
+        block_on(concurrency.acquire());
+        block_on(concurrency.acquire());
+        concurrency.set_limit(1);
+        assert_eq!(0, concurrency.available());
+        concurrency.release();
+        assert_eq!(0, concurrency.available());
+        concurrency.release();
+        assert_eq!(1, concurrency.available());
+        block_on(concurrency.acquire());
+        assert_eq!(0, concurrency.available());
+    }
+
+    #[test]
+    fn test_wait_idle() {
+        let concurrency = Concurrency::new(2);
+        block_on(concurrency.acquire());
+        concurrency.set_limit(1);
+        concurrency.release();
+        block_on(concurrency.wait_idle());
+        assert_eq!(1, concurrency.available());
+    }
+}
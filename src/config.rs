@@ -1,27 +1,450 @@
-use crate::errors::ProcessorError::{self, CommandLineError};
+use crate::backoff::BackoffConfig;
+use crate::circuit::CircuitBreakerConfig;
+use crate::disposition::{Disposition, DEFAULT_RETRY_DELAY};
+use crate::errors::ProcessorError::{self, CommandLineError, InvalidConfig};
+use crate::pool::BlockingPoolConfig;
+use crate::prefetch::PrefetchConfig;
+use crate::priority::Priority;
+use crate::ratelimit::RateLimitConfig;
 use clap::{App, Arg, ArgMatches};
 use rusoto_core::Region;
 use std::default::Default;
 use std::str::FromStr;
+use std::time::Duration;
 
 const DEFAULT_QUEUE: &'static str = "my-messages";
+/// The longest visibility timeout SQS allows
+const MAX_VISIBILITY_TIMEOUT_SECONDS: u64 = 12 * 60 * 60;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Config {
     pub mode: Mode,
+    /// Queue that dead lettered messages are sent to. If not set they are deleted
+    pub dead_letter_queue: Option<QueueURL>,
+    /// Keeps messages hidden while they are being processed. Disabled if not set
+    pub visibility_heartbeat: Option<VisibilityHeartbeat>,
+    /// What happens to a message when the worker panics while processing it
+    pub panic_disposition: Disposition,
+    /// How long a message retried with Disposition::Requeue or a RecoverableError stays hidden
+    pub retry_delay: Duration,
+    /// Maximum time a worker may spend on one message. No limit if not set
+    pub message_timeout: Option<Duration>,
+    /// What happens to a message when the worker times out
+    pub timeout_disposition: Disposition,
+    /// The thread pool used by processors created with Processor::new_blocking
+    pub blocking_pool: BlockingPoolConfig,
+    /// Maximum number of messages processed at the same time
+    pub max_in_flight: usize,
+    /// How long to wait for in flight messages to finish after shutdown is requested
+    pub shutdown_timeout: Duration,
+    /// Shuts the processor down when the process receives SIGTERM or SIGINT. Off by default so
+    /// a processor spawned inside an application leaves signals to it, Processor::run always
+    /// shuts down on them
+    pub shutdown_on_signal: bool,
+    /// How messages are received from the queue
+    pub receive: ReceiveSettings,
+    /// How long to wait before receiving again after consecutive receive errors
+    pub error_backoff: BackoffConfig,
+    /// How long to wait before receiving again after consecutive empty receives.
+    /// The queue is polled again right away if not set, or after an empty long poll
+    pub empty_backoff: Option<BackoffConfig>,
+    /// The tokio runtime created by Processor::run
+    pub runtime: RuntimeConfig,
+    /// How capacity is divided between queues when several queues are consumed
+    pub priority: Priority,
+    /// The longest a queue goes without being received from when queues are consumed by priority
+    pub starvation_timeout: Duration,
+    /// Limits how fast messages are received and handed to workers. Unlimited by default
+    pub rate_limit: RateLimitConfig,
+    /// Stops receiving messages while most of them fail. Disabled if not set
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Receives messages into a bounded buffer ahead of processing them. Disabled if not set
+    pub prefetch: Option<PrefetchConfig>,
 }
 
 impl Config {
     pub fn with_mode(self, mode: Mode) -> Self {
         Config { mode, ..self }
     }
+
+    pub fn with_dead_letter_queue(self, dead_letter_queue: QueueURL) -> Self {
+        Config {
+            dead_letter_queue: Some(dead_letter_queue),
+            ..self
+        }
+    }
+
+    pub fn with_retry_delay(self, retry_delay: Duration) -> Self {
+        Config {
+            retry_delay,
+            ..self
+        }
+    }
+
+    pub fn with_panic_disposition(self, panic_disposition: Disposition) -> Self {
+        Config {
+            panic_disposition,
+            ..self
+        }
+    }
+
+    pub fn with_message_timeout(self, message_timeout: Duration) -> Self {
+        Config {
+            message_timeout: Some(message_timeout),
+            ..self
+        }
+    }
+
+    pub fn with_timeout_disposition(self, timeout_disposition: Disposition) -> Self {
+        Config {
+            timeout_disposition,
+            ..self
+        }
+    }
+
+    pub fn with_blocking_pool(self, blocking_pool: BlockingPoolConfig) -> Self {
+        Config {
+            blocking_pool,
+            ..self
+        }
+    }
+
+    pub fn with_max_in_flight(self, max_in_flight: usize) -> Self {
+        Config {
+            max_in_flight,
+            ..self
+        }
+    }
+
+    pub fn with_shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        Config {
+            shutdown_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_shutdown_on_signal(self, shutdown_on_signal: bool) -> Self {
+        Config {
+            shutdown_on_signal,
+            ..self
+        }
+    }
+
+    pub fn with_receive(self, receive: ReceiveSettings) -> Self {
+        Config { receive, ..self }
+    }
+
+    pub fn with_error_backoff(self, error_backoff: BackoffConfig) -> Self {
+        Config {
+            error_backoff,
+            ..self
+        }
+    }
+
+    pub fn with_empty_backoff(self, empty_backoff: Option<BackoffConfig>) -> Self {
+        Config {
+            empty_backoff,
+            ..self
+        }
+    }
+
+    pub fn with_runtime(self, runtime: RuntimeConfig) -> Self {
+        Config { runtime, ..self }
+    }
+
+    pub fn with_priority(self, priority: Priority) -> Self {
+        Config { priority, ..self }
+    }
+
+    pub fn with_starvation_timeout(self, starvation_timeout: Duration) -> Self {
+        Config {
+            starvation_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_rate_limit(self, rate_limit: RateLimitConfig) -> Self {
+        Config { rate_limit, ..self }
+    }
+
+    pub fn with_circuit_breaker(self, circuit_breaker: CircuitBreakerConfig) -> Self {
+        Config {
+            circuit_breaker: Some(circuit_breaker),
+            ..self
+        }
+    }
+
+    pub fn with_prefetch(self, prefetch: PrefetchConfig) -> Self {
+        Config {
+            prefetch: Some(prefetch),
+            ..self
+        }
+    }
+
+    pub fn with_visibility_heartbeat(self, visibility_heartbeat: VisibilityHeartbeat) -> Self {
+        Config {
+            visibility_heartbeat: Some(visibility_heartbeat),
+            ..self
+        }
+    }
+
+    /// Checks that the settings are within the limits SQS and the processor support
+    pub fn validate(&self) -> Result<(), ProcessorError> {
+        let invalid = |message: &str| Err(InvalidConfig(message.to_owned()));
+        if self.max_in_flight == 0 {
+            return invalid("max_in_flight must be at least 1");
+        }
+        self.receive.validate()?;
+        for backoff in Some(&self.error_backoff)
+            .into_iter()
+            .chain(self.empty_backoff.as_ref())
+        {
+            if backoff.initial > backoff.max || backoff.multiplier == 0 {
+                return invalid(
+                    "back-off initial must not exceed max and multiplier must be at least 1",
+                );
+            }
+        }
+        if let Some(heartbeat) = &self.visibility_heartbeat {
+            if heartbeat.interval == Duration::from_secs(0)
+                || heartbeat.interval >= heartbeat.visibility_timeout
+            {
+                return invalid("heartbeat interval must be greater than zero and less than its visibility_timeout");
+            }
+        }
+        if let Priority::Weighted(weights) = &self.priority {
+            if weights.contains(&0) {
+                return invalid("priority weights must be at least 1");
+            }
+        }
+        if self.retry_delay > Duration::from_secs(MAX_VISIBILITY_TIMEOUT_SECONDS) {
+            return invalid("retry_delay must be at most 12 hours");
+        }
+        if self.starvation_timeout == Duration::from_secs(0) {
+            return invalid("starvation_timeout must be greater than zero");
+        }
+        if !self.rate_limit.is_valid() {
+            return invalid("rate limits must allow more than zero messages per second and a burst of at least 1");
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if !circuit_breaker.is_valid() {
+                return invalid("circuit breaker failure_rate must be between 0 and 1 and minimum_messages between 1 and the window");
+            }
+        }
+        self.validate_prefetch(&self.receive)?;
+        if self.prefetch.is_some() && self.priority != Priority::Independent {
+            return invalid("prefetch cannot be combined with a Strict or Weighted priority");
+        }
+        if self.runtime.core_threads == 0 {
+            return invalid("runtime core_threads must be at least 1");
+        }
+        // tokio starts its core workers on the blocking threads, sync workers need one more
+        if self.runtime.blocking_threads == 0 {
+            return invalid("runtime blocking_threads must be at least 1");
+        }
+        if self.blocking_pool.threads == 0 {
+            return invalid("blocking_pool threads must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Checks the prefetch settings against the receive settings of a queue. Prefetching needs
+    /// the visibility timeout to know when buffered messages expire
+    pub(crate) fn validate_prefetch(
+        &self,
+        receive: &ReceiveSettings,
+    ) -> Result<(), ProcessorError> {
+        match &self.prefetch {
+            Some(prefetch) if !prefetch.is_valid(receive.visibility_timeout) => Err(InvalidConfig(
+                "prefetch needs a visibility_timeout on every queue, a capacity of at least 1 and a release_margin less than the visibility timeout".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             mode: Mode::AWS(Region::UsWest2, DEFAULT_QUEUE.to_owned()),
+            dead_letter_queue: None,
+            visibility_heartbeat: None,
+            panic_disposition: Disposition::Requeue,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            message_timeout: None,
+            timeout_disposition: Disposition::Release,
+            blocking_pool: BlockingPoolConfig::default(),
+            max_in_flight: 1,
+            shutdown_timeout: Duration::from_secs(30),
+            shutdown_on_signal: false,
+            receive: ReceiveSettings::default(),
+            error_backoff: BackoffConfig::new(Duration::from_millis(100), Duration::from_secs(30)),
+            empty_backoff: Some(BackoffConfig::new(
+                Duration::from_millis(100),
+                Duration::from_secs(5),
+            )),
+            runtime: RuntimeConfig::default(),
+            priority: Priority::default(),
+            starvation_timeout: Duration::from_secs(10),
+            rate_limit: RateLimitConfig::default(),
+            circuit_breaker: None,
+            prefetch: None,
+        }
+    }
+}
+
+/// Settings for the tokio runtime Processor::run creates
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeConfig {
+    /// The number of threads async work runs on
+    pub core_threads: usize,
+    /// The number of additional threads for blocking calls, at least 1. Sync workers that
+    /// have a heartbeat or timeout run on these
+    pub blocking_threads: usize,
+    pub thread_name: String,
+    pub thread_stack_size: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            core_threads: 4,
+            blocking_threads: 4,
+            thread_name: "rs-queue-processor".to_owned(),
+            thread_stack_size: 3 * 1024 * 1024,
+        }
+    }
+}
+
+/// Parameters of the receive requests sent to SQS
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReceiveSettings {
+    /// How long a receive waits for messages to arrive. Short polling is used if not set or zero,
+    /// SQS allows at most 20 seconds
+    pub wait_time: Option<Duration>,
+    /// How often the queue is polled when short polling
+    pub poll_interval: Duration,
+    /// The maximum number of messages per receive, SQS allows at most 10
+    pub max_messages: usize,
+    /// The visibility timeout of received messages. The queue default is used if not set
+    pub visibility_timeout: Option<Duration>,
+    /// The message system attributes to receive
+    pub attribute_names: Vec<String>,
+    /// The custom message attributes to receive
+    pub message_attribute_names: Vec<String>,
+}
+
+impl ReceiveSettings {
+    /// Receive settings that wait up to wait_time for messages to arrive
+    pub fn long_polling(wait_time: Duration) -> Self {
+        ReceiveSettings {
+            wait_time: Some(wait_time),
+            ..ReceiveSettings::default()
+        }
+    }
+
+    /// Receive settings that poll the queue every poll_interval without waiting for messages
+    pub fn short_polling(poll_interval: Duration) -> Self {
+        ReceiveSettings {
+            wait_time: None,
+            poll_interval,
+            ..ReceiveSettings::default()
+        }
+    }
+
+    pub fn with_max_messages(self, max_messages: usize) -> Self {
+        ReceiveSettings {
+            max_messages,
+            ..self
+        }
+    }
+
+    pub fn with_visibility_timeout(self, visibility_timeout: Duration) -> Self {
+        ReceiveSettings {
+            visibility_timeout: Some(visibility_timeout),
+            ..self
+        }
+    }
+
+    pub fn with_attribute_names(self, attribute_names: Vec<String>) -> Self {
+        ReceiveSettings {
+            attribute_names,
+            ..self
+        }
+    }
+
+    pub fn with_message_attribute_names(self, message_attribute_names: Vec<String>) -> Self {
+        ReceiveSettings {
+            message_attribute_names,
+            ..self
+        }
+    }
+
+    pub fn is_long_polling(&self) -> bool {
+        self.wait_time
+            .map_or(false, |wait_time| wait_time.as_secs() > 0)
+    }
+
+    /// Checks that the settings are within the limits SQS supports
+    pub fn validate(&self) -> Result<(), ProcessorError> {
+        let invalid = |message: &str| Err(InvalidConfig(message.to_owned()));
+        if self.max_messages == 0 || self.max_messages > 10 {
+            return invalid("max_messages must be between 1 and 10");
+        }
+        if self
+            .wait_time
+            .map_or(false, |w| w > Duration::from_secs(20))
+        {
+            return invalid("wait_time must be at most 20 seconds");
+        }
+        if !self.is_long_polling() && self.poll_interval == Duration::from_secs(0) {
+            return invalid("poll_interval must be greater than zero when short polling");
+        }
+        if self.visibility_timeout.map_or(false, |v| {
+            v > Duration::from_secs(MAX_VISIBILITY_TIMEOUT_SECONDS)
+        }) {
+            return invalid("visibility_timeout must be at most 12 hours");
         }
+        Ok(())
+    }
+}
+
+impl Default for ReceiveSettings {
+    fn default() -> Self {
+        ReceiveSettings {
+            wait_time: Some(Duration::from_secs(20)),
+            poll_interval: Duration::from_millis(100),
+            max_messages: 10,
+            visibility_timeout: None,
+            attribute_names: vec!["All".to_owned()],
+            message_attribute_names: vec!["All".to_owned()],
+        }
+    }
+}
+
+/// Settings for periodically extending the visibility timeout of in flight messages
+#[derive(Debug, PartialEq, Clone)]
+pub struct VisibilityHeartbeat {
+    /// The visibility timeout set on the message by each heartbeat
+    pub visibility_timeout: Duration,
+    /// How often a heartbeat is sent
+    pub interval: Duration,
+    /// The maximum total time a message is kept hidden by heartbeats
+    pub max_extension: Duration,
+}
+
+impl VisibilityHeartbeat {
+    /// Creates heartbeat settings that send a heartbeat every half visibility timeout
+    pub fn new(visibility_timeout: Duration, max_extension: Duration) -> Self {
+        VisibilityHeartbeat {
+            visibility_timeout,
+            interval: visibility_timeout / 2,
+            max_extension,
+        }
+    }
+
+    pub fn with_interval(self, interval: Duration) -> Self {
+        VisibilityHeartbeat { interval, ..self }
     }
 }
 
@@ -133,6 +556,74 @@ fn get_matches<'a>() -> ArgMatches<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+        assert!(Config::default().with_max_in_flight(0).validate().is_err());
+        let receive = ReceiveSettings::default().with_max_messages(11);
+        assert!(Config::default().with_receive(receive).validate().is_err());
+        let retry_delay = Duration::from_secs(13 * 60 * 60);
+        assert!(Config::default()
+            .with_retry_delay(retry_delay)
+            .validate()
+            .is_err());
+        let heartbeat = VisibilityHeartbeat::new(Duration::from_secs(30), Duration::from_secs(300))
+            .with_interval(Duration::from_secs(30));
+        assert!(Config::default()
+            .with_visibility_heartbeat(heartbeat)
+            .validate()
+            .is_err());
+        let circuit_breaker = CircuitBreakerConfig::new(0.0, Duration::from_secs(30));
+        assert!(Config::default()
+            .with_circuit_breaker(circuit_breaker)
+            .validate()
+            .is_err());
+        let prefetch = PrefetchConfig::new(10).with_release_margin(Duration::from_secs(30));
+        assert!(Config::default()
+            .with_prefetch(prefetch)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_runtime() {
+        let runtime = RuntimeConfig {
+            blocking_threads: 0,
+            ..RuntimeConfig::default()
+        };
+        assert!(Config::default().with_runtime(runtime).validate().is_err());
+        let runtime = RuntimeConfig {
+            core_threads: 0,
+            ..RuntimeConfig::default()
+        };
+        assert!(Config::default().with_runtime(runtime).validate().is_err());
+    }
+
+    #[test]
+    fn test_prefetch_with_priority() {
+        let receive = ReceiveSettings::default().with_visibility_timeout(Duration::from_secs(30));
+        let config = Config::default()
+            .with_receive(receive)
+            .with_prefetch(PrefetchConfig::new(10));
+        assert!(config.validate().is_ok());
+        assert!(config.with_priority(Priority::Strict).validate().is_err());
+    }
+
+    #[test]
+    fn test_prefetch_requires_visibility_timeout() {
+        let config = Config::default().with_prefetch(PrefetchConfig::new(10));
+        assert!(config.validate().is_err());
+        let receive = ReceiveSettings::default().with_visibility_timeout(Duration::from_secs(30));
+        assert!(config.with_receive(receive).validate().is_ok());
+    }
+
+    #[test]
+    fn test_is_long_polling() {
+        assert!(ReceiveSettings::default().is_long_polling());
+        assert!(!ReceiveSettings::short_polling(Duration::from_secs(1)).is_long_polling());
+        assert!(!ReceiveSettings::long_polling(Duration::from_millis(500)).is_long_polling());
+    }
+
     #[test]
     fn test_missing_queue() {
         let cli = Cli::new_with(Some("23".to_owned()), Some("uswest2".to_owned()), None);
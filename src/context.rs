@@ -0,0 +1,188 @@
+use crate::cancel::CancellationToken;
+#[cfg(test)]
+use crate::disposition::Disposition;
+use crate::errors::ProcessorError;
+use crate::sqs::SqsClient;
+#[cfg(test)]
+use crate::work::AsyncWorker;
+use rusoto_sqs::Message as SqsMessage;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+const RECEIVE_COUNT_ATTRIBUTE: &'static str = "ApproximateReceiveCount";
+const FIRST_RECEIVE_TIMESTAMP_ATTRIBUTE: &'static str = "ApproximateFirstReceiveTimestamp";
+
+/// Information about a message that is handed to the worker alongside the message
+#[derive(Clone)]
+pub struct MessageContext {
+    receive_count: u32,
+    first_received: Option<SystemTime>,
+    deadline: Option<Instant>,
+    visibility: VisibilityHandle,
+    cancellation: CancellationToken,
+    shutdown: CancellationToken,
+}
+
+impl MessageContext {
+    pub(crate) fn new(
+        message: &SqsMessage,
+        sqs_client: SqsClient,
+        cancellation: CancellationToken,
+        shutdown: CancellationToken,
+        deadline: Option<Instant>,
+    ) -> Self {
+        MessageContext {
+            receive_count: receive_count(message),
+            first_received: first_received(message),
+            deadline,
+            visibility: VisibilityHandle {
+                sqs_client,
+                receipt_handle: message.receipt_handle.clone(),
+            },
+            cancellation,
+            shutdown,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn for_test(message: &SqsMessage) -> Self {
+        let sqs_client = SqsClient::new(rusoto_core::Region::UsWest2, "test-queue");
+        MessageContext::new(
+            message,
+            sqs_client,
+            CancellationToken::new(),
+            CancellationToken::new(),
+            None,
+        )
+    }
+
+    /// The approximate number of times the message has been received, including this time
+    pub fn receive_count(&self) -> u32 {
+        self.receive_count
+    }
+
+    /// The approximate time the message was first received
+    pub fn first_received(&self) -> Option<SystemTime> {
+        self.first_received
+    }
+
+    /// The time at which processing of the message times out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The url of the queue the message was received from
+    pub fn queue_url(&self) -> &str {
+        self.visibility.sqs_client.queue_url.as_ref()
+    }
+
+    /// Handle for changing the visibility timeout of the message
+    pub fn visibility(&self) -> &VisibilityHandle {
+        &self.visibility
+    }
+
+    /// Token that is cancelled when the message times out, or is still being processed when
+    /// the processor shutdown timeout expires
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Token that is cancelled as soon as processor shutdown is requested, so long running work
+    /// can wrap up early. The message is still given the shutdown timeout to finish
+    pub fn shutdown(&self) -> &CancellationToken {
+        &self.shutdown
+    }
+}
+
+/// Runs the worker on the message with a test context and returns its Disposition
+#[cfg(test)]
+pub(crate) fn process_for_test<W>(worker: &W, message: SqsMessage) -> Disposition
+where
+    W: AsyncWorker + ?Sized,
+{
+    let context = MessageContext::for_test(&message);
+    futures::executor::block_on(worker.process(message, context))
+}
+
+/// Handle for changing the visibility timeout of a message while it is being processed
+#[derive(Clone)]
+pub struct VisibilityHandle {
+    sqs_client: SqsClient,
+    receipt_handle: Option<String>,
+}
+
+impl VisibilityHandle {
+    pub fn receipt_handle(&self) -> Option<&str> {
+        self.receipt_handle.as_ref().map(|r| r.as_ref())
+    }
+
+    /// Keeps the message hidden for the specified duration from now
+    pub async fn extend(&self, timeout: Duration) -> Result<(), ProcessorError> {
+        match &self.receipt_handle {
+            Some(receipt_handle) => {
+                self.sqs_client
+                    .change_message_visibility(receipt_handle.as_ref(), timeout.as_secs() as i64)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn receive_count(message: &SqsMessage) -> u32 {
+    message
+        .attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get(RECEIVE_COUNT_ATTRIBUTE))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(1)
+}
+
+fn first_received(message: &SqsMessage) -> Option<SystemTime> {
+    message
+        .attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get(FIRST_RECEIVE_TIMESTAMP_ATTRIBUTE))
+        .and_then(|millis| millis.parse().ok())
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn message(attributes: Vec<(&str, &str)>) -> SqsMessage {
+        let attributes = attributes
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect::<HashMap<String, String>>();
+        SqsMessage {
+            attributes: Some(attributes),
+            ..SqsMessage::default()
+        }
+    }
+
+    #[test]
+    fn test_receive_count() {
+        assert_eq!(
+            3,
+            receive_count(&message(vec![(RECEIVE_COUNT_ATTRIBUTE, "3")]))
+        );
+        assert_eq!(1, receive_count(&message(vec![])));
+    }
+
+    #[test]
+    fn test_first_received() {
+        let m = message(vec![(FIRST_RECEIVE_TIMESTAMP_ATTRIBUTE, "1500")]);
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_millis(1500)),
+            first_received(&m)
+        );
+        assert_eq!(None, first_received(&message(vec![])));
+    }
+}
@@ -0,0 +1,71 @@
+use crate::errors::WorkError;
+use std::time::Duration;
+
+/// Default delay before a requeued message becomes visible again, see Config::retry_delay
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// What should happen to a message once a worker is done with it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Disposition {
+    /// The message was handled and will be deleted
+    Ack,
+    /// The message will be made visible again after the specified delay
+    Retry(Duration),
+    /// The message will be made visible again after the retry delay of the processor
+    Requeue,
+    /// The message will be moved to the dead letter queue with the specified reason.
+    /// If no dead letter queue is configured the message is deleted.
+    DeadLetter(String),
+    /// The message will be made visible again immediately
+    Release,
+    /// Nothing is done, the message becomes visible again when its visibility timeout expires
+    Leave,
+    /// The visibility timeout of the message will be set to the specified duration from now
+    ExtendVisibility(Duration),
+}
+
+/// A RecoverableError retries the message after the retry delay of the processor,
+/// an UnRecoverableError dead letters the message
+impl From<WorkError> for Disposition {
+    fn from(e: WorkError) -> Self {
+        match e {
+            WorkError::RecoverableError(_) => Disposition::Requeue,
+            WorkError::UnRecoverableError(msg) => Disposition::DeadLetter(msg),
+        }
+    }
+}
+
+impl From<Result<(), WorkError>> for Disposition {
+    fn from(result: Result<(), WorkError>) -> Self {
+        match result {
+            Ok(()) => Disposition::Ack,
+            Err(e) => Disposition::from(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_work_error() {
+        assert_eq!(
+            Disposition::Requeue,
+            Disposition::from(WorkError::RecoverableError("busy".to_owned()))
+        );
+        assert_eq!(
+            Disposition::DeadLetter("bad".to_owned()),
+            Disposition::from(WorkError::UnRecoverableError("bad".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_from_result() {
+        assert_eq!(Disposition::Ack, Disposition::from(Ok(())));
+        assert_eq!(
+            Disposition::Requeue,
+            Disposition::from(Err(WorkError::RecoverableError("busy".to_owned())))
+        );
+    }
+}
@@ -1,13 +1,16 @@
 use rusoto_core::request::HttpDispatchError as RusotoHttpDispatchError;
 use rusoto_core::RusotoError;
 use rusoto_credential::CredentialsError as RusotoCredentialsError;
-use rusoto_sqs::{DeleteMessageError, ReceiveMessageError, SendMessageError};
+use rusoto_sqs::{
+    ChangeMessageVisibilityError, DeleteMessageError, ReceiveMessageError, SendMessageError,
+};
 use std::convert::From;
 use std::error::Error;
 use std::fmt::{self, Display};
 use std::io::Error as IOError;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum ProcessorError {
@@ -15,10 +18,14 @@ pub enum ProcessorError {
     SqsReceiveMessageError(Arc<RusotoError<ReceiveMessageError>>),
     SqsDeleteMessageError(Arc<RusotoError<DeleteMessageError>>),
     SqsSendMessageError(Arc<RusotoError<SendMessageError>>),
+    SqsChangeMessageVisibilityError(Arc<RusotoError<ChangeMessageVisibilityError>>),
     CredentialsError(Arc<RusotoCredentialsError>),
     HttpDispatchError(Arc<RusotoHttpDispatchError>),
     CommandLineError(&'static str),
+    InvalidConfig(String),
     WorkErrorOccurred(WorkError),
+    MessageTimeout(Option<String>, Duration),
+    WorkerStartFailed(WorkError),
     Unknown,
 }
 
@@ -45,9 +52,19 @@ impl<'a> Display for ProcessorError {
             ProcessorError::CommandLineError(e) => {
                 write!(f, "A command line error occurred: {}", e)
             }
+            ProcessorError::InvalidConfig(e) => write!(f, "Invalid processor configuration: {}", e),
             ProcessorError::Unknown => write!(f, "An unknown error occurred"),
             ProcessorError::WorkErrorOccurred(e) => write!(f, "A work error occurred: {}", e),
+            ProcessorError::WorkerStartFailed(e) => write!(f, "The worker failed to start: {}", e),
+            ProcessorError::MessageTimeout(message_id, timeout) => write!(
+                f,
+                "Message {:?} was abandoned after timing out after {:?}",
+                message_id, timeout
+            ),
             ProcessorError::SqsSendMessageError(e) => write!(f, "Error Sending message {}", e),
+            ProcessorError::SqsChangeMessageVisibilityError(e) => {
+                write!(f, "Error changing message visibility {}", e)
+            }
         }
     }
 }
@@ -61,7 +78,9 @@ impl Error for ProcessorError {
             ProcessorError::HttpDispatchError(ref e) => Some(e.as_ref()),
             ProcessorError::SqsDeleteMessageError(ref e) => Some(e.as_ref()),
             ProcessorError::WorkErrorOccurred(ref we) => Some(we),
+            ProcessorError::WorkerStartFailed(ref we) => Some(we),
             ProcessorError::SqsSendMessageError(ref e) => Some(e.as_ref()),
+            ProcessorError::SqsChangeMessageVisibilityError(ref e) => Some(e.as_ref()),
             _ => None,
         }
     }
@@ -103,6 +122,12 @@ impl From<RusotoError<SendMessageError>> for ProcessorError {
     }
 }
 
+impl From<RusotoError<ChangeMessageVisibilityError>> for ProcessorError {
+    fn from(e: RusotoError<ChangeMessageVisibilityError>) -> Self {
+        ProcessorError::SqsChangeMessageVisibilityError(Arc::new(e))
+    }
+}
+
 impl From<WorkError> for ProcessorError {
     fn from(e: WorkError) -> Self {
         ProcessorError::WorkErrorOccurred(e)
@@ -131,3 +156,20 @@ impl<'a> Display for WorkError {
 }
 
 impl Error for WorkError {}
+
+#[derive(Debug, Clone)]
+pub enum CodecError {
+    MissingBody,
+    InvalidBody(String),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::MissingBody => write!(f, "Message contains no body"),
+            CodecError::InvalidBody(msg) => write!(f, "Message body could not be decoded: {}", msg),
+        }
+    }
+}
+
+impl Error for CodecError {}
@@ -0,0 +1,158 @@
+use crate::cancel::CancellationToken;
+use crate::circuit::CircuitBreaker;
+use crate::concurrency::Concurrency;
+use crate::errors::ProcessorError;
+use crate::pool::BlockingPool;
+use crate::stats::{Stats, StatsSnapshot};
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Whether a processor may receive messages, shared by a processor and its handles
+#[derive(Clone, Default)]
+pub(crate) struct Pause {
+    inner: Arc<PauseInner>,
+}
+
+#[derive(Default)]
+struct PauseInner {
+    paused: AtomicBool,
+    /// Cancelled when the processor is resumed, replaced when it is paused
+    resumed: Mutex<CancellationToken>,
+}
+
+impl Pause {
+    /// Returns false if the processor was already paused
+    fn pause(&self) -> bool {
+        let mut resumed = self.inner.resumed.lock().unwrap();
+        if self.inner.paused.load(Ordering::SeqCst) {
+            return false;
+        }
+        *resumed = CancellationToken::new();
+        self.inner.paused.store(true, Ordering::SeqCst);
+        true
+    }
+
+    /// Returns false if the processor was not paused
+    fn resume(&self) -> bool {
+        let resumed = self.inner.resumed.lock().unwrap();
+        if !self.inner.paused.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.inner.paused.store(false, Ordering::SeqCst);
+        resumed.cancel();
+        true
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::SeqCst)
+    }
+
+    /// Returns a future that completes once the processor is not paused
+    pub(crate) async fn resumed(&self) {
+        let resumed = {
+            let resumed = self.inner.resumed.lock().unwrap();
+            if !self.inner.paused.load(Ordering::SeqCst) {
+                return;
+            }
+            resumed.clone()
+        };
+        resumed.cancelled().await
+    }
+}
+
+/// Controls a processor while it is running
+///
+/// Handles are cheap to clone and can be taken before the processor is spawned:
+///
+/// let handle = processor.handle();
+/// tokio::spawn(async move { processor.process().await });
+/// handle.pause();
+/// handle.set_max_in_flight(4)?;
+/// handle.resume();
+#[derive(Clone)]
+pub struct ProcessorHandle {
+    pub(crate) shutdown: CancellationToken,
+    pub(crate) pause: Pause,
+    pub(crate) concurrency: Arc<Concurrency>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) blocking_pool: Option<BlockingPool>,
+    pub(crate) circuit_breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl ProcessorHandle {
+    /// Stops receiving messages until resume is called. Messages already received are
+    /// still processed
+    pub fn pause(&self) {
+        if self.pause.pause() {
+            info!("Processor paused");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.pause.resume() {
+            info!("Processor resumed");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// Stops the processor. No more messages are fetched and process returns once the messages
+    /// being processed are handled, or the shutdown timeout expires
+    pub fn shutdown(&self) {
+        info!("Shutdown requested");
+        self.shutdown.cancel()
+    }
+
+    /// Changes the maximum number of messages processed at the same time. When lowered,
+    /// messages already being processed are allowed to finish
+    pub fn set_max_in_flight(&self, max_in_flight: usize) -> Result<(), ProcessorError> {
+        if max_in_flight == 0 {
+            return Err(ProcessorError::InvalidConfig(
+                "max_in_flight must be at least 1".to_owned(),
+            ));
+        }
+        info!("Changing max_in_flight to {}", max_in_flight);
+        self.concurrency.set_limit(max_in_flight);
+        Ok(())
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.concurrency.limit()
+    }
+
+    /// Returns a snapshot of the counters of the processor
+    pub fn stats(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            blocking_pool: self.blocking_pool.as_ref().map(|pool| pool.stats()),
+            circuit: self
+                .circuit_breaker
+                .as_ref()
+                .map(|circuit_breaker| circuit_breaker.state()),
+            max_in_flight: self.concurrency.limit(),
+            paused: self.pause.is_paused(),
+            ..self.stats.snapshot()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_pause_and_resume() {
+        let pause = Pause::default();
+        block_on(pause.resumed());
+        assert!(pause.pause());
+        assert!(!pause.pause());
+        assert!(pause.is_paused());
+        let resumed = pause.resumed();
+        assert!(pause.resume());
+        assert!(!pause.resume());
+        block_on(resumed);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::context::MessageContext;
+use crate::disposition::Disposition;
+use crate::errors::WorkError;
+use crate::work::AsyncWorker;
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, info};
+use rusoto_sqs::Message as SqsMessage;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Trait to implement to wrap a worker with additional behavior
+///
+/// The wrapped worker should implement AsyncWorker, so any Worker can be layered.
+pub trait Layer<W> {
+    /// The worker produced by wrapping the inner worker
+    type Worker;
+
+    fn layer(&self, inner: W) -> Self::Worker;
+}
+
+/// Builds a worker out of a stack of layers
+///
+/// The first layer added is the outermost layer and sees the message first:
+///
+/// let worker = WorkerBuilder::new()
+///     .layer(LoggingLayer)
+///     .layer(TimingLayer::default())
+///     .worker(WorkerImpl::default());
+/// let processor = Processor::new(&config, Box::new(worker));
+pub struct WorkerBuilder<L> {
+    layer: L,
+}
+
+impl WorkerBuilder<Identity> {
+    pub fn new() -> Self {
+        WorkerBuilder { layer: Identity }
+    }
+}
+
+impl Default for WorkerBuilder<Identity> {
+    fn default() -> Self {
+        WorkerBuilder::new()
+    }
+}
+
+impl<L> WorkerBuilder<L> {
+    /// Adds a layer inside of the layers added so far
+    pub fn layer<T>(self, layer: T) -> WorkerBuilder<Stack<T, L>> {
+        WorkerBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    /// Wraps the worker with all of the layers
+    pub fn worker<W>(&self, worker: W) -> L::Worker
+    where
+        L: Layer<W>,
+    {
+        self.layer.layer(worker)
+    }
+}
+
+/// Layer that leaves the worker unchanged
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<W> Layer<W> for Identity {
+    type Worker = W;
+
+    fn layer(&self, inner: W) -> W {
+        inner
+    }
+}
+
+/// Two layers applied one inside of the other
+#[derive(Debug, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<W, Inner, Outer> Layer<W> for Stack<Inner, Outer>
+where
+    Inner: Layer<W>,
+    Outer: Layer<Inner::Worker>,
+{
+    type Worker = Outer::Worker;
+
+    fn layer(&self, worker: W) -> Self::Worker {
+        self.outer.layer(self.inner.layer(worker))
+    }
+}
+
+/// Layer that logs every message and the Disposition the worker returned for it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingLayer;
+
+impl<W> Layer<W> for LoggingLayer {
+    type Worker = Logging<W>;
+
+    fn layer(&self, inner: W) -> Logging<W> {
+        Logging { inner }
+    }
+}
+
+pub struct Logging<W> {
+    inner: W,
+}
+
+impl<W: AsyncWorker> AsyncWorker for Logging<W> {
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition> {
+        let message_id = message.message_id.clone();
+        debug!(
+            "Processing message: {:?} receive count: {}",
+            &message_id,
+            context.receive_count()
+        );
+        self.inner
+            .process(message, context)
+            .map(move |disposition| {
+                info!(
+                    "Processed message: {:?} disposition: {:?}",
+                    &message_id, &disposition
+                );
+                disposition
+            })
+            .boxed()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        self.inner.on_start()
+    }
+
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        self.inner.on_idle()
+    }
+
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        self.inner.on_shutdown()
+    }
+}
+
+type TimingCallback = dyn Fn(Duration, &Disposition) + Send + Sync;
+
+/// Layer that measures how long the worker takes for each message
+///
+/// By default the time is logged, a callback can be supplied to record it elsewhere.
+#[derive(Clone)]
+pub struct TimingLayer {
+    on_complete: Arc<TimingCallback>,
+}
+
+impl TimingLayer {
+    pub fn new<F>(on_complete: F) -> Self
+    where
+        F: Fn(Duration, &Disposition) + Send + Sync + 'static,
+    {
+        TimingLayer {
+            on_complete: Arc::new(on_complete),
+        }
+    }
+}
+
+impl Default for TimingLayer {
+    fn default() -> Self {
+        TimingLayer::new(|elapsed, disposition| {
+            debug!(
+                "Message processed in {:?} disposition: {:?}",
+                elapsed, disposition
+            )
+        })
+    }
+}
+
+impl<W> Layer<W> for TimingLayer {
+    type Worker = Timing<W>;
+
+    fn layer(&self, inner: W) -> Timing<W> {
+        Timing {
+            inner,
+            on_complete: Arc::clone(&self.on_complete),
+        }
+    }
+}
+
+pub struct Timing<W> {
+    inner: W,
+    on_complete: Arc<TimingCallback>,
+}
+
+impl<W: AsyncWorker> AsyncWorker for Timing<W> {
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition> {
+        let started = Instant::now();
+        let on_complete = Arc::clone(&self.on_complete);
+        self.inner
+            .process(message, context)
+            .map(move |disposition| {
+                on_complete(started.elapsed(), &disposition);
+                disposition
+            })
+            .boxed()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        self.inner.on_start()
+    }
+
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        self.inner.on_idle()
+    }
+
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        self.inner.on_shutdown()
+    }
+}
+
+/// Layer that maps the Disposition returned by the worker, e.g. to retry instead of dead letter
+#[derive(Clone, Copy)]
+pub struct MapDispositionLayer {
+    f: fn(Disposition) -> Disposition,
+}
+
+impl MapDispositionLayer {
+    pub fn new(f: fn(Disposition) -> Disposition) -> Self {
+        MapDispositionLayer { f }
+    }
+}
+
+impl<W> Layer<W> for MapDispositionLayer {
+    type Worker = MapDisposition<W>;
+
+    fn layer(&self, inner: W) -> MapDisposition<W> {
+        MapDisposition { inner, f: self.f }
+    }
+}
+
+pub struct MapDisposition<W> {
+    inner: W,
+    f: fn(Disposition) -> Disposition,
+}
+
+impl<W: AsyncWorker> AsyncWorker for MapDisposition<W> {
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition> {
+        self.inner.process(message, context).map(self.f).boxed()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        self.inner.on_start()
+    }
+
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        self.inner.on_idle()
+    }
+
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        self.inner.on_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::process_for_test;
+    use crate::work::Worker;
+
+    struct DeadLetterWorker;
+
+    impl Worker for DeadLetterWorker {
+        fn process(&self, _message: SqsMessage, _context: MessageContext) -> Disposition {
+            Disposition::DeadLetter("bad message".to_owned())
+        }
+    }
+
+    fn retry_dead_letters(disposition: Disposition) -> Disposition {
+        match disposition {
+            Disposition::DeadLetter(_) => Disposition::Requeue,
+            other => other,
+        }
+    }
+
+    #[test]
+    fn test_map_disposition() {
+        let worker = MapDispositionLayer::new(retry_dead_letters).layer(DeadLetterWorker);
+        assert_eq!(
+            Disposition::Requeue,
+            process_for_test(&worker, SqsMessage::default())
+        );
+    }
+
+    #[test]
+    fn test_stack_order() {
+        let worker = WorkerBuilder::new()
+            .layer(MapDispositionLayer::new(retry_dead_letters))
+            .layer(MapDispositionLayer::new(|_| {
+                Disposition::DeadLetter("inner layer".to_owned())
+            }))
+            .worker(DeadLetterWorker);
+        assert_eq!(
+            Disposition::Requeue,
+            process_for_test(&worker, SqsMessage::default())
+        );
+    }
+
+    #[test]
+    fn test_timing_callback() {
+        let worker = WorkerBuilder::new()
+            .layer(LoggingLayer)
+            .layer(TimingLayer::new(|_, disposition| {
+                assert_eq!(
+                    &Disposition::DeadLetter("bad message".to_owned()),
+                    disposition
+                )
+            }))
+            .worker(DeadLetterWorker);
+        match process_for_test(&worker, SqsMessage::default()) {
+            Disposition::DeadLetter(_) => (),
+            other => panic!("Unexpected disposition: {:?}", other),
+        }
+    }
+}
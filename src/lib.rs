@@ -1,6 +1,23 @@
 //#![feature(async_closure)]
+pub mod backoff;
+pub mod builder;
+pub mod cancel;
+pub mod circuit;
+pub mod codec;
+mod concurrency;
 pub mod config;
+pub mod context;
+pub mod disposition;
 pub mod errors;
+pub mod handle;
+pub mod layer;
+pub mod pool;
+pub mod prefetch;
+pub mod priority;
 pub mod processor;
+pub mod ratelimit;
+pub mod router;
 mod sqs;
+pub mod stats;
+pub mod typed;
 pub mod work;
@@ -0,0 +1,263 @@
+use crate::context::MessageContext;
+use crate::disposition::Disposition;
+use crate::errors::{ProcessorError, WorkError};
+use crate::work::{AsyncWorker, Worker};
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt};
+use log::{trace, warn};
+use rusoto_sqs::Message as SqsMessage;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Settings for the thread pool sync workers run on when created with Processor::new_blocking
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlockingPoolConfig {
+    /// The number of threads in the pool
+    pub threads: usize,
+    /// The maximum number of messages waiting for a thread. Messages beyond that are rejected
+    pub queue_depth: usize,
+}
+
+impl Default for BlockingPoolConfig {
+    fn default() -> Self {
+        BlockingPoolConfig {
+            threads: 4,
+            queue_depth: 16,
+        }
+    }
+}
+
+/// A point in time copy of the blocking pool counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockingPoolStats {
+    pub threads: usize,
+    pub queue_depth: usize,
+    /// The number of messages waiting for a thread
+    pub queued: usize,
+    /// The number of messages being processed
+    pub active: usize,
+    pub completed: u64,
+    /// The number of messages rejected because the queue was full
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// A fixed size pool of threads for running blocking work off of the async runtime
+///
+/// The threads exit once every clone of the pool has been dropped.
+#[derive(Clone)]
+pub struct BlockingPool {
+    config: BlockingPoolConfig,
+    sender: Arc<Mutex<SyncSender<Job>>>,
+    counters: Arc<Counters>,
+}
+
+impl BlockingPool {
+    pub fn new(config: BlockingPoolConfig) -> Result<Self, ProcessorError> {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::default());
+
+        for i in 0..config.threads {
+            let receiver = Arc::clone(&receiver);
+            let counters = Arc::clone(&counters);
+            thread::Builder::new()
+                .name(format!("rs-queue-processor-blocking-{}", i))
+                .spawn(move || run_jobs(receiver, counters))?;
+        }
+
+        Ok(BlockingPool {
+            config,
+            sender: Arc::new(Mutex::new(sender)),
+            counters,
+        })
+    }
+
+    pub fn stats(&self) -> BlockingPoolStats {
+        BlockingPoolStats {
+            threads: self.config.threads,
+            queue_depth: self.config.queue_depth,
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            active: self.counters.active.load(Ordering::Relaxed),
+            completed: self.counters.completed.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Queues the job, returns false if the queue is full
+    fn try_execute<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        match self.sender.lock().unwrap().try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+fn run_jobs(receiver: Arc<Mutex<Receiver<Job>>>, counters: Arc<Counters>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => {
+                counters.queued.fetch_sub(1, Ordering::SeqCst);
+                counters.active.fetch_add(1, Ordering::SeqCst);
+                job();
+                counters.active.fetch_sub(1, Ordering::SeqCst);
+                counters.completed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                trace!("Blocking pool closed, stopping thread");
+                break;
+            }
+        }
+    }
+}
+
+/// AsyncWorker that runs a sync Worker on a BlockingPool
+///
+/// Messages that do not fit in the pool queue get the rejected Disposition, which defaults
+/// to retrying the message. A timed out message is abandoned but keeps its pool thread
+/// until the worker returns.
+pub struct BlockingWorker<W> {
+    worker: Arc<W>,
+    pool: BlockingPool,
+    rejected: Disposition,
+}
+
+impl<W> BlockingWorker<W> {
+    pub fn new(worker: W, pool: BlockingPool) -> Self {
+        BlockingWorker {
+            worker: Arc::new(worker),
+            pool,
+            rejected: Disposition::Requeue,
+        }
+    }
+
+    /// Sets the Disposition of messages rejected because the pool queue is full
+    pub fn with_rejected_disposition(self, rejected: Disposition) -> Self {
+        BlockingWorker { rejected, ..self }
+    }
+
+    pub fn pool(&self) -> &BlockingPool {
+        &self.pool
+    }
+}
+
+impl<W> AsyncWorker for BlockingWorker<W>
+where
+    W: Worker + Send + Sync + 'static,
+{
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition> {
+        let (sender, receiver) = oneshot::channel();
+        let worker = Arc::clone(&self.worker);
+        let message_id = message.message_id.clone();
+        let job = move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                Worker::process(&*worker, message, context)
+            }));
+            let _ = sender.send(result);
+        };
+
+        if !self.pool.try_execute(job) {
+            warn!(
+                "Blocking pool is full, rejecting message: {:?}",
+                &message_id
+            );
+            return future::ready(self.rejected.clone()).boxed();
+        }
+
+        async move {
+            match receiver.await {
+                Ok(Ok(disposition)) => disposition,
+                // resume the panic so it is handled like any other worker panic
+                Ok(Err(payload)) => panic::resume_unwind(payload),
+                Err(_) => panic!("Blocking pool dropped message: {:?}", &message_id),
+            }
+        }
+        .boxed()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Worker::timeout(&*self.worker)
+    }
+
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        future::ready(Worker::on_start(&*self.worker)).boxed()
+    }
+
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        future::ready(Worker::on_idle(&*self.worker)).boxed()
+    }
+
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        future::ready(Worker::on_shutdown(&*self.worker)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::process_for_test;
+    use crate::work::worker_fn;
+
+    fn pool(threads: usize, queue_depth: usize) -> BlockingPool {
+        BlockingPool::new(BlockingPoolConfig {
+            threads,
+            queue_depth,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_runs_worker_on_pool() {
+        let worker = worker_fn(|_message: SqsMessage| {
+            assert!(thread::current()
+                .name()
+                .unwrap()
+                .starts_with("rs-queue-processor-blocking"));
+            Disposition::Ack
+        });
+        let worker = BlockingWorker::new(worker, pool(1, 1));
+        assert_eq!(
+            Disposition::Ack,
+            process_for_test(&worker, SqsMessage::default())
+        );
+        assert_eq!(0, worker.pool().stats().queued);
+    }
+
+    #[test]
+    fn test_rejects_when_full() {
+        let pool = pool(1, 1);
+        let (release, wait) = mpsc::channel::<()>();
+        assert!(pool.try_execute(move || wait.recv().unwrap()));
+        while pool.stats().active == 0 {
+            thread::yield_now();
+        }
+
+        assert!(pool.try_execute(|| ()));
+        assert!(!pool.try_execute(|| ()));
+        assert_eq!(1, pool.stats().queued);
+        assert_eq!(1, pool.stats().rejected);
+        release.send(()).unwrap();
+    }
+}
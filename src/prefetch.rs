@@ -0,0 +1,204 @@
+use rusoto_sqs::Message as SqsMessage;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+
+/// Settings for receiving messages ahead of processing them
+///
+/// Messages are received into a bounded buffer on their own, so receiving does not wait on
+/// processing and processing does not wait on SQS. Only applies to queues whose worker
+/// processes one message at a time and that are not consumed by priority.
+///
+/// The processor cannot tell the visibility timeout a queue applies by default, so every
+/// queue has to set ReceiveSettings::visibility_timeout when prefetching.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PrefetchConfig {
+    /// The most messages of a queue that are received but not yet processed
+    pub capacity: usize,
+    /// Buffered messages that become visible again within this margin are released instead
+    /// of processed late
+    pub release_margin: Duration,
+}
+
+impl PrefetchConfig {
+    pub fn new(capacity: usize) -> Self {
+        PrefetchConfig {
+            capacity,
+            release_margin: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_release_margin(self, release_margin: Duration) -> Self {
+        PrefetchConfig {
+            release_margin,
+            ..self
+        }
+    }
+
+    pub(crate) fn is_valid(&self, visibility_timeout: Option<Duration>) -> bool {
+        visibility_timeout.map_or(false, |visibility_timeout| {
+            self.capacity > 0 && self.release_margin < visibility_timeout
+        })
+    }
+}
+
+/// A received message waiting in the prefetch buffer
+#[derive(Debug)]
+pub(crate) struct Prefetched {
+    pub(crate) message: SqsMessage,
+    /// When the message becomes visible to other consumers again
+    deadline: Instant,
+}
+
+impl Prefetched {
+    pub(crate) fn new(
+        message: SqsMessage,
+        received_at: Instant,
+        visibility_timeout: Duration,
+    ) -> Self {
+        Prefetched {
+            message,
+            deadline: received_at + visibility_timeout,
+        }
+    }
+
+    /// Whether the message becomes visible again within the margin
+    pub(crate) fn expires_within(&self, margin: Duration, now: Instant) -> bool {
+        now + margin >= self.deadline
+    }
+}
+
+/// Creates a buffer that holds up to capacity messages received with the visibility timeout
+pub(crate) fn buffer(
+    capacity: usize,
+    visibility_timeout: Duration,
+) -> (PrefetchSender, PrefetchReceiver) {
+    let room = Arc::new(Semaphore::new(capacity));
+    let (sender, receiver) = mpsc::channel(capacity);
+    (
+        PrefetchSender {
+            room: Arc::clone(&room),
+            sender,
+            visibility_timeout,
+        },
+        PrefetchReceiver { room, receiver },
+    )
+}
+
+pub(crate) struct PrefetchSender {
+    room: Arc<Semaphore>,
+    sender: mpsc::Sender<Prefetched>,
+    visibility_timeout: Duration,
+}
+
+impl PrefetchSender {
+    /// Wraps messages received at received_at for the buffer
+    pub(crate) fn prefetched(
+        &self,
+        messages: Vec<SqsMessage>,
+        received_at: Instant,
+    ) -> Vec<Prefetched> {
+        messages
+            .into_iter()
+            .map(|message| Prefetched::new(message, received_at, self.visibility_timeout))
+            .collect()
+    }
+
+    /// Waits until there is room in the buffer and returns how many messages fit
+    pub(crate) async fn room(&self) -> usize {
+        let permit = self.room.acquire().await;
+        let room = self.room.available_permits() + 1;
+        drop(permit);
+        room
+    }
+
+    /// Adds the messages to the buffer, which must have room for them.
+    /// Returns the messages that were not added if the receiver is gone
+    pub(crate) async fn send(&mut self, messages: Vec<Prefetched>) -> Result<(), Vec<SqsMessage>> {
+        let mut messages = messages.into_iter();
+        while let Some(prefetched) = messages.next() {
+            self.room.acquire().await.forget();
+            if let Err(mpsc::error::SendError(prefetched)) = self.sender.send(prefetched).await {
+                self.room.add_permits(1);
+                let unsent = Some(prefetched).into_iter().chain(messages);
+                return Err(unsent.map(|prefetched| prefetched.message).collect());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct PrefetchReceiver {
+    room: Arc<Semaphore>,
+    receiver: mpsc::Receiver<Prefetched>,
+}
+
+impl PrefetchReceiver {
+    /// Takes the next message out of the buffer. Returns None once the sender is gone
+    pub(crate) async fn recv(&mut self) -> Option<Prefetched> {
+        let prefetched = self.receiver.recv().await;
+        if prefetched.is_some() {
+            self.room.add_permits(1);
+        }
+        prefetched
+    }
+
+    /// Stops the buffer from taking more messages and returns the messages left in it
+    pub(crate) async fn close(mut self) -> Vec<SqsMessage> {
+        self.receiver.close();
+        let mut left = vec![];
+        while let Some(prefetched) = self.receiver.recv().await {
+            left.push(prefetched.message);
+        }
+        left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn prefetched(now: Instant) -> Prefetched {
+        Prefetched::new(SqsMessage::default(), now, Duration::from_secs(30))
+    }
+
+    #[test]
+    fn test_expires_within() {
+        let now = Instant::now();
+        let prefetched = prefetched(now);
+        let margin = Duration::from_secs(5);
+        assert!(!prefetched.expires_within(margin, now + Duration::from_secs(24)));
+        assert!(prefetched.expires_within(margin, now + Duration::from_secs(25)));
+    }
+
+    #[test]
+    fn test_requires_visibility_timeout() {
+        let prefetch = PrefetchConfig::new(10);
+        assert!(!prefetch.is_valid(None));
+        assert!(prefetch.is_valid(Some(Duration::from_secs(30))));
+        assert!(!prefetch.is_valid(Some(Duration::from_secs(5))));
+    }
+
+    #[test]
+    fn test_room() {
+        let now = Instant::now();
+        let (mut sender, mut receiver) = buffer(3, Duration::from_secs(30));
+        assert_eq!(3, block_on(sender.room()));
+        assert!(block_on(sender.send(vec![prefetched(now), prefetched(now)])).is_ok());
+        assert_eq!(1, block_on(sender.room()));
+        assert!(block_on(receiver.recv()).is_some());
+        assert_eq!(2, block_on(sender.room()));
+        assert_eq!(1, block_on(receiver.close()).len());
+    }
+
+    #[test]
+    fn test_send_after_close() {
+        let now = Instant::now();
+        let (mut sender, receiver) = buffer(2, Duration::from_secs(30));
+        block_on(receiver.close());
+        let unsent = block_on(sender.send(vec![prefetched(now), prefetched(now)]));
+        assert_eq!(Some(2), unsent.err().map(|messages| messages.len()));
+        assert_eq!(2, block_on(sender.room()));
+    }
+}
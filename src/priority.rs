@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+/// How a processor consuming several queues divides its capacity between them
+///
+/// With Strict or Weighted every receive is a short poll, whatever the wait_time of the
+/// queues, so an empty high priority queue does not hold up the queues after it. Messages
+/// are not prefetched either.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Priority {
+    /// Every queue is received from on its own, whenever there is room for more messages
+    Independent,
+    /// Queues are received from in the order they were added, a queue only once the queues
+    /// before it are empty
+    Strict,
+    /// Queues are received from in proportion to their weights, given in the order the
+    /// queues were added. Weights of 5 and 1 receive from the first queue five times as often
+    Weighted(Vec<u32>),
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Independent
+    }
+}
+
+/// Decides which queue to receive from next when queues are consumed by priority
+///
+/// A queue that has not been received from for the starvation timeout is received from
+/// first, so low priority queues are never starved by busy high priority queues.
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    priority: Priority,
+    starvation_timeout: Duration,
+    /// Smooth weighted round robin state, one entry per queue
+    current_weights: Vec<i64>,
+    last_received: Vec<Instant>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        priority: Priority,
+        starvation_timeout: Duration,
+        queues: usize,
+        now: Instant,
+    ) -> Self {
+        Scheduler {
+            priority,
+            starvation_timeout,
+            current_weights: vec![0; queues],
+            last_received: vec![now; queues],
+        }
+    }
+
+    /// Returns the queues to try receiving from, in order, until one has messages
+    pub(crate) fn order(&mut self, now: Instant) -> Vec<usize> {
+        let mut starved = (0..self.last_received.len())
+            .filter(|&queue| {
+                now.duration_since(self.last_received[queue]) >= self.starvation_timeout
+            })
+            .collect::<Vec<_>>();
+        starved.sort_by_key(|&queue| self.last_received[queue]);
+
+        let weights = match &self.priority {
+            Priority::Weighted(weights) => Some(weights.clone()),
+            Priority::Strict | Priority::Independent => None,
+        };
+        let by_priority = match weights {
+            Some(weights) => self.weighted_order(weights),
+            None => (0..self.last_received.len()).collect(),
+        };
+        starved
+            .iter()
+            .cloned()
+            .chain(
+                by_priority
+                    .into_iter()
+                    .filter(|queue| !starved.contains(queue)),
+            )
+            .collect()
+    }
+
+    /// Records that the queue was received from
+    pub(crate) fn received(&mut self, queue: usize, now: Instant) {
+        self.last_received[queue] = now;
+    }
+
+    /// The next queue picked by smooth weighted round robin, then the rest by weight
+    fn weighted_order(&mut self, weights: Vec<u32>) -> Vec<usize> {
+        let total = weights.iter().map(|&weight| i64::from(weight)).sum::<i64>();
+        for (current, &weight) in self.current_weights.iter_mut().zip(&weights) {
+            *current += i64::from(weight);
+        }
+        let mut order = (0..weights.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&queue| std::cmp::Reverse(self.current_weights[queue]));
+        if let Some(&picked) = order.first() {
+            self.current_weights[picked] -= total;
+        }
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(priority: Priority, now: Instant) -> Scheduler {
+        Scheduler::new(priority, Duration::from_secs(10), 2, now)
+    }
+
+    #[test]
+    fn test_strict_order() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(Priority::Strict, now);
+        assert_eq!(vec![0, 1], scheduler.order(now));
+        assert_eq!(vec![0, 1], scheduler.order(now));
+    }
+
+    #[test]
+    fn test_weighted_order() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(Priority::Weighted(vec![5, 1]), now);
+        let first_picks = (0..12).map(|_| scheduler.order(now)[0]).collect::<Vec<_>>();
+        assert_eq!(10, first_picks.iter().filter(|&&queue| queue == 0).count());
+        assert_eq!(2, first_picks.iter().filter(|&&queue| queue == 1).count());
+    }
+
+    #[test]
+    fn test_starved_queue_first() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(Priority::Strict, now);
+        let later = now + Duration::from_secs(11);
+        scheduler.received(0, later);
+        assert_eq!(vec![1, 0], scheduler.order(later));
+        scheduler.received(1, later);
+        assert_eq!(vec![0, 1], scheduler.order(later));
+    }
+}
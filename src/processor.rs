@@ -1,114 +1,1220 @@
-use crate::config::{Config, Mode};
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::builder::ProcessorBuilder;
+use crate::cancel::CancellationToken;
+use crate::circuit::{CircuitBreaker, CircuitState};
+use crate::concurrency::Concurrency;
+use crate::config::{Config, Mode, QueueURL, ReceiveSettings, RuntimeConfig, VisibilityHeartbeat};
+use crate::context::MessageContext;
+use crate::disposition::Disposition;
 use crate::errors::{ProcessorError, WorkError};
-use crate::sqs::SqsClient;
-use crate::work::Worker;
-use log::{debug, error, info, trace};
+use crate::handle::{Pause, ProcessorHandle};
+use crate::pool::{BlockingPool, BlockingWorker};
+use crate::prefetch::{self, PrefetchConfig, PrefetchReceiver, PrefetchSender};
+use crate::priority::{Priority, Scheduler};
+use crate::ratelimit::RateLimiter;
+use crate::sqs::{SqsClient, MAX_RECEIVE_MESSAGES};
+use crate::stats::{Stats, StatsSnapshot};
+use crate::work::{async_worker_fn, worker_fn, AsyncWorker, BatchWorker, Worker};
+use futures::executor;
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use log::{debug, error, info, trace, warn};
 use rusoto_sqs::Message as SqsMessage;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
-use tokio::time::{self, Instant, Duration};
+use std::time::Instant as StdInstant;
+use tokio::runtime::Builder;
+use tokio::signal;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
 
-// todo: make configurable
-/// Default requeue delay in seconds
-const DEFAULT_REQUEUE_DELAY: i64 = 10;
+pub(crate) type ShareableWorker = dyn AsyncWorker + Send + Sync;
+pub(crate) type ShareableBatchWorker = dyn BatchWorker + Send + Sync;
+pub(crate) type Hook = Arc<dyn Fn() + Send + Sync>;
+pub(crate) type CircuitHook = Arc<dyn Fn(CircuitState) + Send + Sync>;
 
-type ShareableWorker = dyn Worker + Send + Sync;
+/// The kind of worker messages are dispatched to
+#[derive(Clone)]
+pub(crate) enum WorkerKind {
+    Single(Arc<ShareableWorker>),
+    Batch(Arc<ShareableBatchWorker>),
+}
+
+impl WorkerKind {
+    async fn on_start(&self) -> Result<(), WorkError> {
+        match self {
+            WorkerKind::Single(worker) => worker.on_start().await,
+            WorkerKind::Batch(worker) => worker.on_start().await,
+        }
+    }
+
+    async fn on_idle(&self) {
+        match self {
+            WorkerKind::Single(worker) => worker.on_idle().await,
+            WorkerKind::Batch(worker) => worker.on_idle().await,
+        }
+    }
+
+    async fn on_shutdown(&self) {
+        match self {
+            WorkerKind::Single(worker) => worker.on_shutdown().await,
+            WorkerKind::Batch(worker) => worker.on_shutdown().await,
+        }
+    }
+}
+
+/// A queue the processor receives messages from and the worker its messages are handed to
+#[derive(Clone)]
+pub(crate) struct QueueConsumer {
+    pub(crate) sqs_client: SqsClient,
+    pub(crate) receive: ReceiveSettings,
+    pub(crate) worker: WorkerKind,
+}
+
+/// Callbacks the processor runs after the matching worker lifecycle hooks
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub(crate) on_start: Vec<Hook>,
+    pub(crate) on_idle: Vec<Hook>,
+    pub(crate) on_shutdown: Vec<Hook>,
+    pub(crate) on_circuit_change: Vec<CircuitHook>,
+}
+
+fn run_hooks(hooks: &[Hook]) {
+    for hook in hooks {
+        hook()
+    }
+}
 
 /// This is the main class for processing messages from an SQS Queue
 ///
 /// To instantiate an instance of Processor you will need:
 /// * A configuration object.
-/// * A Worker instance that supports both Send and Sync
+/// * A Worker, AsyncWorker or BatchWorker instance that supports both Send and Sync
+///
+/// Additional queues, each with their own worker, can be added with the ProcessorBuilder.
+/// All queues share the in flight limit and are shut down together.
+///
+/// While it is running the processor is controlled through a ProcessorHandle, see handle.
 #[derive(Clone)]
 pub struct Processor {
-    sqs_client: SqsClient,
-    worker: Arc<ShareableWorker>,
+    queues: Vec<QueueConsumer>,
+    dead_letter_queue: Option<QueueURL>,
+    error_backoff: BackoffConfig,
+    empty_backoff: Option<BackoffConfig>,
+    visibility_heartbeat: Option<VisibilityHeartbeat>,
+    panic_disposition: Disposition,
+    retry_delay: Duration,
+    message_timeout: Option<Duration>,
+    timeout_disposition: Disposition,
+    priority: Priority,
+    starvation_timeout: Duration,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    prefetch: Option<PrefetchConfig>,
+    hooks: Hooks,
+    shutdown: CancellationToken,
+    /// Cancelled when in flight messages did not finish before the shutdown timeout
+    abandoned: CancellationToken,
+    shutdown_timeout: Duration,
+    shutdown_on_signal: bool,
+    runtime: RuntimeConfig,
+    stats: Arc<Stats>,
+    blocking_pool: Option<BlockingPool>,
+    /// One slot per message that may be processed at the same time
+    concurrency: Arc<Concurrency>,
+    pause: Pause,
 }
 
 impl Processor {
     /// Instantiates a new instance of the process
     pub fn new(config: &Config, worker: Box<ShareableWorker>) -> Result<Self, ProcessorError> {
+        Processor::with_worker_kind(config, WorkerKind::Single(Arc::from(worker)))
+    }
+
+    /// Instantiates a new instance of the process that runs a sync worker on a dedicated
+    /// thread pool, so CPU heavy work does not block fetching and deleting messages.
+    /// The pool is sized by Config::blocking_pool
+    pub fn new_blocking<W>(config: &Config, worker: W) -> Result<Self, ProcessorError>
+    where
+        W: Worker + Send + Sync + 'static,
+    {
+        let pool = BlockingPool::new(config.blocking_pool.clone())?;
+        let worker = BlockingWorker::new(worker, pool.clone());
+        let processor = Processor::new(config, Box::new(worker))?;
+        Ok(processor.with_blocking_pool(pool))
+    }
+
+    /// Instantiates a new instance of the process with a function or closure as the worker
+    ///
+    /// let processor = Processor::from_fn(&config, |message| {
+    ///     println!("Received message: {:?}", message);
+    ///     Disposition::Ack
+    /// });
+    pub fn from_fn<F, R>(config: &Config, f: F) -> Result<Self, ProcessorError>
+    where
+        F: Fn(SqsMessage) -> R + Send + Sync + 'static,
+        R: Into<Disposition> + 'static,
+    {
+        Processor::new(config, Box::new(worker_fn(f)))
+    }
+
+    /// Instantiates a new instance of the process with a function or closure
+    /// returning a future as the worker
+    pub fn from_async_fn<F, Fut, R>(config: &Config, f: F) -> Result<Self, ProcessorError>
+    where
+        F: Fn(SqsMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: Into<Disposition> + 'static,
+    {
+        Processor::new(config, Box::new(async_worker_fn(f)))
+    }
+
+    /// Instantiates a new instance of the process that hands each received batch to a BatchWorker
+    pub fn new_batch(
+        config: &Config,
+        worker: Box<ShareableBatchWorker>,
+    ) -> Result<Self, ProcessorError> {
+        Processor::with_worker_kind(config, WorkerKind::Batch(Arc::from(worker)))
+    }
+
+    /// Returns a builder for configuring every aspect of a processor
+    pub fn builder(config: Config) -> ProcessorBuilder {
+        ProcessorBuilder::new(config)
+    }
+
+    fn with_worker_kind(config: &Config, worker: WorkerKind) -> Result<Self, ProcessorError> {
+        config.validate()?;
+        let queue = QueueConsumer {
+            sqs_client: build_sqs_client(&config.mode),
+            receive: config.receive.clone(),
+            worker,
+        };
+        Ok(Processor::from_parts(config, vec![queue], Hooks::default()))
+    }
+
+    /// Creates the processor from a validated config
+    pub(crate) fn from_parts(config: &Config, queues: Vec<QueueConsumer>, hooks: Hooks) -> Self {
         info!("Initializing rs-queue-processor: {:?}", &config.mode);
-        let sqs_client = build_sqs_client(&config.mode);
+        Processor {
+            queues,
+            dead_letter_queue: config.dead_letter_queue.clone(),
+            error_backoff: config.error_backoff.clone(),
+            empty_backoff: config.empty_backoff.clone(),
+            visibility_heartbeat: config.visibility_heartbeat.clone(),
+            panic_disposition: config.panic_disposition.clone(),
+            retry_delay: config.retry_delay,
+            message_timeout: config.message_timeout,
+            timeout_disposition: config.timeout_disposition.clone(),
+            priority: config.priority.clone(),
+            starvation_timeout: config.starvation_timeout,
+            rate_limiter: Some(&config.rate_limit)
+                .filter(|limit| limit.global.is_some() || limit.per_key.is_some())
+                .map(|limit| Arc::new(RateLimiter::new(limit.clone(), StdInstant::now()))),
+            circuit_breaker: config.circuit_breaker.as_ref().map(|circuit_breaker| {
+                Arc::new(CircuitBreaker::new(
+                    circuit_breaker.clone(),
+                    StdInstant::now(),
+                ))
+            }),
+            prefetch: config.prefetch.clone(),
+            hooks,
+            shutdown: CancellationToken::new(),
+            abandoned: CancellationToken::new(),
+            shutdown_timeout: config.shutdown_timeout,
+            shutdown_on_signal: config.shutdown_on_signal,
+            runtime: config.runtime.clone(),
+            stats: Arc::new(Stats::default()),
+            blocking_pool: None,
+            concurrency: Arc::new(Concurrency::new(config.max_in_flight)),
+            pause: Pause::default(),
+        }
+    }
+
+    pub(crate) fn with_blocking_pool(self, blocking_pool: BlockingPool) -> Self {
+        Processor {
+            blocking_pool: Some(blocking_pool),
+            ..self
+        }
+    }
+
+    /// Returns a handle that controls this processor while it is running
+    pub fn handle(&self) -> ProcessorHandle {
+        ProcessorHandle {
+            shutdown: self.shutdown.clone(),
+            pause: self.pause.clone(),
+            concurrency: Arc::clone(&self.concurrency),
+            stats: Arc::clone(&self.stats),
+            blocking_pool: self.blocking_pool.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+        }
+    }
 
-        // let runtime = Builder::new()
-        //     .blocking_threads(4)
-        //     .core_threads(4)
-        //     .keep_alive(Some(Duration::from_secs(60)))
-        //     .name_prefix("rs-queue-processor-")
-        //     .stack_size(3 * 1024 * 1024)
-        //     .build()
-        //     .map_err(ProcessorError::from)?;
+    /// Returns a snapshot of the counters of this processor
+    pub fn stats(&self) -> StatsSnapshot {
+        self.handle().stats()
+    }
 
-        Ok(Processor {
-            sqs_client,
-            worker: Arc::from(worker),
-        })
+    /// Stops the processor. No more messages are fetched and process returns once the messages
+    /// being processed are handled, or the shutdown timeout expires
+    pub fn shutdown(&self) {
+        self.handle().shutdown()
+    }
+
+    /// Runs the processor on a new tokio runtime until it shuts down, blocking the current thread
+    ///
+    /// For applications without a runtime of their own. The runtime is configured by
+    /// Config::runtime and SIGTERM and SIGINT shut it down, whatever Config::shutdown_on_signal
+    /// is set to.
+    ///
+    /// let processor = Processor::new(&config, Box::new(worker))?;
+    /// processor.run()?;
+    pub fn run(&self) -> Result<(), ProcessorError> {
+        let mut runtime = Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .core_threads(self.runtime.core_threads)
+            .max_threads(self.runtime.core_threads + self.runtime.blocking_threads)
+            .thread_name(self.runtime.thread_name.as_str())
+            .thread_stack_size(self.runtime.thread_stack_size)
+            .build()?;
+        runtime.block_on(self.process_until_shutdown(true))
     }
 
     /// Generates a Interval Task that can be executed
     ///
+    /// The worker on_start hooks are awaited first, if one fails the processor does not start.
+    /// Every queue is consumed until shutdown is called or, if enabled in the Config, SIGTERM
+    /// or SIGINT is received. In flight messages are then given the shutdown timeout
+    /// to finish, after which the worker on_shutdown hooks are awaited.
+    ///
     /// let processor = Processor::new(&config, worker);
-    /// tokio::run(processor.process());
-    pub async fn process(&self) {
+    /// tokio::spawn(async move { processor.process().await });
+    pub async fn process(&self) -> Result<(), ProcessorError> {
+        self.process_until_shutdown(self.shutdown_on_signal).await
+    }
+
+    async fn process_until_shutdown(&self, shutdown_on_signal: bool) -> Result<(), ProcessorError> {
         trace!("process called!!");
-        // Clone required for the move in for_each. Cloning SqsClient is cheap as the underlying Rusoto client is embedded in an Arc
-        let self_clone = self.clone();
-        let mut interval = time::interval_at(Instant::now(), Duration::from_millis(100));
+        self.start_workers().await?;
+        run_hooks(&self.hooks.on_start);
+        if shutdown_on_signal {
+            self.spawn_signal_handler();
+        }
+
+        if self.queues.len() > 1 && self.priority != Priority::Independent {
+            self.consume_by_priority().await;
+        } else {
+            future::join_all(self.queues.iter().map(|queue| self.consume_queue(queue))).await;
+        }
+
+        info!("Processor stopped fetching messages, waiting for in flight messages");
+        if !self.drain().await {
+            warn!(
+                "{} messages did not finish within {:?}, abandoning them",
+                self.stats.snapshot().in_flight,
+                &self.shutdown_timeout
+            );
+            self.abandoned.cancel();
+        }
+
+        info!("Processor stopped, calling on_shutdown");
+        shutdown_workers(&self.queues).await;
+        run_hooks(&self.hooks.on_shutdown);
+        Ok(())
+    }
+
+    /// Calls on_start of every worker. If one fails, the workers that already started are
+    /// shut down again
+    async fn start_workers(&self) -> Result<(), ProcessorError> {
+        for (started, queue) in self.queues.iter().enumerate() {
+            if let Err(e) = queue.worker.on_start().await {
+                error!("Worker failed to start: {}", &e);
+                shutdown_workers(&self.queues[..started]).await;
+                return Err(ProcessorError::WorkerStartFailed(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Consumes the queue through a prefetch buffer if one is configured and the worker of the
+    /// queue processes one message at a time
+    async fn consume_queue(&self, queue: &QueueConsumer) {
+        let visibility_timeout = queue.receive.visibility_timeout;
+        match (&self.prefetch, &queue.worker, visibility_timeout) {
+            (Some(prefetch), WorkerKind::Single(worker), Some(visibility_timeout)) => {
+                let (sender, receiver) = prefetch::buffer(prefetch.capacity, visibility_timeout);
+                let processing =
+                    self.process_prefetched(queue, worker, prefetch.release_margin, receiver);
+                future::join(self.consume(queue, Some(sender)), processing).await;
+            }
+            _ => self.consume(queue, None).await,
+        }
+    }
+
+    /// Receives messages from the queue until shutdown is requested. Messages are dispatched
+    /// right away, or added to the prefetch buffer if there is one
+    async fn consume(&self, queue: &QueueConsumer, mut buffer: Option<PrefetchSender>) {
+        debug!("Consuming queue: {}", &queue.sqs_client.queue_url);
+        // long polls wait on SQS for messages, so they are only paced when short polling
+        let mut interval = if queue.receive.is_long_polling() {
+            None
+        } else {
+            Some(time::interval_at(
+                Instant::now(),
+                queue.receive.poll_interval,
+            ))
+        };
+        let mut backoff = self.receive_backoff(queue.receive.is_long_polling());
+        let mut idle = false;
         loop {
-            let instant = interval.tick().await;
-            trace!("Timer task is starting: instant {:?}", &instant);
-            let clone_2 = self_clone.clone();
-            clone_2.process_messages().await;
+            match interval.as_mut() {
+                Some(interval) => {
+                    let tick = interval.tick().boxed();
+                    match future::select(tick, self.shutdown.cancelled().boxed()).await {
+                        Either::Left((instant, _)) => {
+                            trace!("Timer task is starting: instant {:?}", &instant)
+                        }
+                        Either::Right(_) => break,
+                    }
+                }
+                None if self.shutdown.is_cancelled() => break,
+                None => (),
+            }
+            let received = match buffer.as_mut() {
+                Some(buffer) => self.prefetch_messages(queue, buffer).await,
+                None => self.process_messages(queue).await,
+            };
+            let delay = match received {
+                Some(received) => {
+                    self.update_idle(queue, &mut idle, received).await;
+                    backoff.after_receive(received)
+                }
+                None if self.shutdown.is_cancelled() => None,
+                None => Some(backoff.after_error()),
+            };
+            if let Some(delay) = delay {
+                self.back_off(delay).await;
+            }
+        }
+    }
+
+    /// Receives from the queues in the order the priority picks until shutdown is requested
+    ///
+    /// Every receive is a short poll, so an empty high priority queue does not hold up the
+    /// queues after it. Rounds in which no queue had messages are paced by the empty back-off,
+    /// or the poll interval of the first queue if there is none.
+    async fn consume_by_priority(&self) {
+        let queues = self
+            .queues
+            .iter()
+            .map(|queue| QueueConsumer {
+                receive: ReceiveSettings {
+                    wait_time: None,
+                    ..queue.receive.clone()
+                },
+                ..queue.clone()
+            })
+            .collect::<Vec<_>>();
+        let mut scheduler = Scheduler::new(
+            self.priority.clone(),
+            self.starvation_timeout,
+            queues.len(),
+            StdInstant::now(),
+        );
+        // every receive is a short poll, so empty rounds are paced by the empty back-off
+        let mut backoff = self.receive_backoff(false);
+        let mut idle = vec![false; queues.len()];
+        while !self.shutdown.is_cancelled() {
+            let mut received = 0;
+            let mut failed = false;
+            for index in scheduler.order(StdInstant::now()) {
+                let queue = &queues[index];
+                match self.process_messages(queue).await {
+                    Some(count) => {
+                        scheduler.received(index, StdInstant::now());
+                        self.update_idle(queue, &mut idle[index], count).await;
+                        received = count;
+                        if count > 0 {
+                            break;
+                        }
+                    }
+                    None if self.shutdown.is_cancelled() => return,
+                    None => failed = true,
+                }
+            }
+
+            let delay = if received > 0 {
+                backoff.after_receive(received)
+            } else if failed {
+                Some(backoff.after_error())
+            } else {
+                let poll_interval = queues[0].receive.poll_interval;
+                Some(backoff.after_receive(0).unwrap_or(poll_interval))
+            };
+            if let Some(delay) = delay {
+                self.back_off(delay).await;
+            }
+        }
+    }
+
+    /// Calls the on_idle hooks when the queue becomes empty
+    async fn update_idle(&self, queue: &QueueConsumer, idle: &mut bool, received: usize) {
+        if received == 0 && !*idle {
+            debug!(
+                "Queue {} is empty, calling on_idle",
+                &queue.sqs_client.queue_url
+            );
+            queue.worker.on_idle().await;
+            run_hooks(&self.hooks.on_idle);
         }
+        *idle = received == 0;
+    }
+
+    /// A long poll already waited for messages, so it is not followed by the empty back-off
+    fn receive_backoff(&self, long_polling: bool) -> ReceiveBackoff {
+        let empty = if long_polling {
+            None
+        } else {
+            self.empty_backoff.clone()
+        };
+        ReceiveBackoff {
+            error: Backoff::new(self.error_backoff.clone()),
+            empty: empty.map(Backoff::new),
+        }
+    }
+
+    /// Waits for the delay, or until shutdown is requested
+    async fn back_off(&self, delay: Duration) {
+        debug!("Backing off for {:?}", &delay);
+        let delayed = time::delay_for(delay).boxed();
+        future::select(delayed, self.shutdown.cancelled().boxed()).await;
     }
 
     /// Returns a future that will fetch messages from
-    /// SQS to be processed
-    async fn process_messages(&self) {
+    /// SQS to be processed. Resolves to the number of messages received
+    ///
+    /// Messages are processed on their own tasks, only as many messages are fetched as there
+    /// are free in flight slots. Waits for a slot to free up if there are none, but does not
+    /// hold it while receiving, so a long poll does not take a slot from the other queues.
+    async fn process_messages(&self, queue: &QueueConsumer) -> Option<usize> {
         trace!("process_messages called!");
-        match self.sqs_client.fetch_messages().await {
+        if !self.wait_until_resumed().await || !self.wait_for_slot().await {
+            return None;
+        }
+        let max_messages = receive_size(
+            queue,
+            self.concurrency.available(),
+            self.concurrency.limit(),
+        );
+        let messages = self.receive(queue, max_messages).await?;
+        let received = messages.len();
+        match &queue.worker {
+            WorkerKind::Single(worker) => {
+                let mut messages = messages.into_iter();
+                while let Some(message) = messages.next() {
+                    if !self.acquire_slot().await {
+                        let unstarted = Some(message).into_iter().chain(messages);
+                        self.release_messages(queue, unstarted.collect()).await;
+                        break;
+                    }
+                    self.spawn_message(queue.clone(), Arc::clone(worker), message);
+                }
+            }
+            WorkerKind::Batch(worker) => {
+                let mut allowed = vec![];
+                let mut messages = messages.into_iter();
+                while let Some(message) = messages.next() {
+                    if !self.wait_for_rate_limit(&message).await {
+                        let unstarted = allowed.into_iter().chain(Some(message)).chain(messages);
+                        self.release_messages(queue, unstarted.collect()).await;
+                        return Some(received);
+                    }
+                    allowed.push(message);
+                }
+                if !allowed.is_empty() {
+                    match self.acquire_slots(allowed.len()).await {
+                        Some(held) => {
+                            self.process_batch(queue, worker, allowed).await;
+                            self.release_slots(held);
+                        }
+                        None => self.release_messages(queue, allowed).await,
+                    }
+                }
+            }
+        }
+        Some(received)
+    }
+
+    /// Receives messages into the prefetch buffer, only as many as there is room for.
+    /// Resolves to the number of messages received
+    async fn prefetch_messages(
+        &self,
+        queue: &QueueConsumer,
+        buffer: &mut PrefetchSender,
+    ) -> Option<usize> {
+        trace!("prefetch_messages called!");
+        if !self.wait_until_resumed().await {
+            return None;
+        }
+        let room =
+            match future::select(buffer.room().boxed(), self.shutdown.cancelled().boxed()).await {
+                Either::Left((room, _)) => room,
+                Either::Right(_) => return None,
+            };
+        let messages = self.receive(queue, room).await?;
+        let received = messages.len();
+        let prefetched = buffer.prefetched(messages, StdInstant::now());
+        if let Err(unsent) = buffer.send(prefetched).await {
+            self.release_messages(queue, unsent).await;
+        }
+        Some(received)
+    }
+
+    /// Takes messages out of the prefetch buffer and processes each on its own task until
+    /// shutdown is requested, then releases the messages left in the buffer
+    ///
+    /// Messages that become visible again within the release margin are released instead,
+    /// so they are not processed at the same time by another consumer.
+    async fn process_prefetched(
+        &self,
+        queue: &QueueConsumer,
+        worker: &Arc<ShareableWorker>,
+        release_margin: Duration,
+        mut buffer: PrefetchReceiver,
+    ) {
+        loop {
+            let next = buffer.recv().boxed();
+            let prefetched = match future::select(next, self.shutdown.cancelled().boxed()).await {
+                Either::Left((Some(prefetched), _)) => prefetched,
+                _ => break,
+            };
+            // the slot is only taken once there is a message, so an idle buffer holds none
+            if !self.acquire_slot().await {
+                self.release_messages(queue, vec![prefetched.message]).await;
+                break;
+            }
+            // waiting for the slot takes time too, so expiry is checked after it
+            if prefetched.expires_within(release_margin, StdInstant::now()) {
+                warn!(
+                    "Message {:?} waited too long in the prefetch buffer, releasing it",
+                    &prefetched.message.message_id
+                );
+                self.concurrency.release();
+                self.release_messages(queue, vec![prefetched.message]).await;
+                continue;
+            }
+            self.spawn_message(queue.clone(), Arc::clone(worker), prefetched.message);
+        }
+        let unstarted = buffer.close().await;
+        if !unstarted.is_empty() {
+            self.release_messages(queue, unstarted).await;
+        }
+    }
+
+    /// Receives up to max_messages messages from the queue, fewer if the rate limit or the
+    /// circuit breaker allow fewer. Returns None if shutdown was requested or receiving failed
+    async fn receive(&self, queue: &QueueConsumer, max_messages: usize) -> Option<Vec<SqsMessage>> {
+        // don't receive more messages than the rate limit lets through
+        let rate_limit_capacity = self.rate_limit_capacity().await?;
+        // don't receive while the circuit is open, only a trial message while it is half open
+        let circuit_capacity = self.circuit_capacity().await?;
+        let max_messages = max_messages
+            .min(MAX_RECEIVE_MESSAGES)
+            .min(rate_limit_capacity.max(1))
+            .min(circuit_capacity);
+        let fetch = queue
+            .sqs_client
+            .fetch_messages(&queue.receive, max_messages)
+            .boxed();
+        // a long poll can take a while, stop waiting on it if shutdown is requested
+        let result = match future::select(fetch, self.shutdown.cancelled().boxed()).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => {
+                self.circuit_received(&[]);
+                return None;
+            }
+        };
+        match result {
             Ok(messages) => {
                 debug!("fetch messages result: {:?}", &messages);
-                for message in messages {
-                    debug!("process_messages: handling {:?}", &message);
-                    let result = self.process_message(message.clone()).await;
-                    if let Err(e) = result {
-                        error!("Error processing message: {:?} error: {}", &message, &e);
+                self.circuit_received(&messages);
+                Some(messages)
+            }
+            Err(err) => {
+                error!("Error fetching messages: {}", err);
+                self.circuit_received(&[]);
+                None
+            }
+        }
+    }
+
+    /// Waits for a free in flight slot and takes it. Returns false if shutdown was requested
+    async fn acquire_slot(&self) -> bool {
+        if self.shutdown.is_cancelled() {
+            return false;
+        }
+        let acquire = self.concurrency.acquire().boxed();
+        match future::select(acquire, self.shutdown.cancelled().boxed()).await {
+            Either::Left(_) => true,
+            Either::Right(_) => false,
+        }
+    }
+
+    /// Waits until a slot is free without taking it. Returns false if shutdown was requested
+    async fn wait_for_slot(&self) -> bool {
+        if !self.acquire_slot().await {
+            return false;
+        }
+        self.concurrency.release();
+        true
+    }
+
+    /// Takes a slot for each of count messages processed together, or as many as the limit
+    /// allows. Returns the number of slots taken, or None if shutdown was requested
+    async fn acquire_slots(&self, count: usize) -> Option<usize> {
+        let mut held = 0;
+        // the limit is checked again for every slot in case it is lowered meanwhile
+        while held < count.min(self.concurrency.limit()) {
+            if !self.acquire_slot().await {
+                self.release_slots(held);
+                return None;
+            }
+            held += 1;
+        }
+        Some(held)
+    }
+
+    fn release_slots(&self, held: usize) {
+        for _ in 0..held {
+            self.concurrency.release();
+        }
+    }
+
+    /// Waits while the processor is paused. Returns false if shutdown was requested
+    async fn wait_until_resumed(&self) -> bool {
+        if self.pause.is_paused() {
+            debug!("Processor is paused, waiting to be resumed");
+            let resumed = self.pause.resumed().boxed();
+            future::select(resumed, self.shutdown.cancelled().boxed()).await;
+        }
+        !self.shutdown.is_cancelled()
+    }
+
+    /// Waits until the rate limit allows the message. Returns false if shutdown was requested
+    async fn wait_for_rate_limit(&self, message: &SqsMessage) -> bool {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            while let Err(wait) = rate_limiter.try_acquire(message, StdInstant::now()) {
+                trace!("Rate limited message: {:?}", &message.message_id);
+                self.back_off(wait).await;
+                if self.shutdown.is_cancelled() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Waits until the global rate limit allows a message and returns how many it allows.
+    /// Returns None if shutdown was requested
+    async fn rate_limit_capacity(&self) -> Option<usize> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => loop {
+                match rate_limiter.available(StdInstant::now()) {
+                    Ok(capacity) => return Some(capacity),
+                    Err(wait) => {
+                        self.back_off(wait).await;
+                        if self.shutdown.is_cancelled() {
+                            return None;
+                        }
                     }
                 }
+            },
+            None => Some(usize::max_value()),
+        }
+    }
+
+    /// Waits until the circuit breaker allows receiving and returns how many messages it
+    /// allows. Returns None if shutdown was requested
+    async fn circuit_capacity(&self) -> Option<usize> {
+        match &self.circuit_breaker {
+            Some(circuit_breaker) => loop {
+                let (allowed, changed) = circuit_breaker.allow_receive(StdInstant::now());
+                if let Some(state) = changed {
+                    self.circuit_changed(state);
+                }
+                match allowed {
+                    Ok(capacity) => return Some(capacity),
+                    Err(wait) => {
+                        self.back_off(wait).await;
+                        if self.shutdown.is_cancelled() {
+                            return None;
+                        }
+                    }
+                }
+            },
+            None => Some(usize::max_value()),
+        }
+    }
+
+    fn circuit_received(&self, messages: &[SqsMessage]) {
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker.received(messages);
+        }
+    }
+
+    /// Tells the circuit breaker the message goes back to the queue without an outcome
+    fn circuit_released(&self, message: &SqsMessage) {
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker.released(message);
+        }
+    }
+
+    /// Records the outcome of a processed message with the circuit breaker
+    fn record_outcome(&self, message: &SqsMessage, failed: bool) {
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if let Some(state) = circuit_breaker.record(message, failed, StdInstant::now()) {
+                self.circuit_changed(state);
+            }
+        }
+    }
+
+    /// Logs the new circuit state and calls the on_circuit_change hooks
+    fn circuit_changed(&self, state: CircuitState) {
+        match state {
+            CircuitState::Open => warn!("Circuit breaker opened, no messages will be received"),
+            CircuitState::HalfOpen => info!("Circuit breaker half open, receiving a trial message"),
+            CircuitState::Closed => info!("Circuit breaker closed, receiving messages again"),
+        }
+        for hook in &self.hooks.on_circuit_change {
+            hook(state)
+        }
+    }
+
+    /// Makes messages that were received but not started visible again right away
+    async fn release_messages(&self, queue: &QueueConsumer, messages: Vec<SqsMessage>) {
+        info!("Releasing {} unstarted messages", messages.len());
+        let releases = messages.into_iter().map(|message| {
+            self.circuit_released(&message);
+            self.handle_disposition(queue, Disposition::Release, message)
+        });
+        for result in future::join_all(releases).await {
+            if let Err(e) = result {
+                error!("Error releasing message: {}", &e);
             }
-            Err(err) => error!("Error fetching messages: {}", err),
         }
     }
 
+    /// Waits for the in flight messages to finish. Returns false if the shutdown timeout expired
+    async fn drain(&self) -> bool {
+        time::timeout(self.shutdown_timeout, self.concurrency.wait_idle())
+            .await
+            .is_ok()
+    }
+
+    /// Shuts the processor down when SIGTERM or SIGINT is received
+    fn spawn_signal_handler(&self) {
+        let processor = self.clone();
+        tokio::spawn(async move {
+            let signalled = shutdown_signal().boxed();
+            match future::select(signalled, processor.shutdown.cancelled().boxed()).await {
+                Either::Left((Ok(()), _)) => {
+                    info!("Received shutdown signal");
+                    processor.shutdown();
+                }
+                Either::Left((Err(e), _)) => {
+                    error!("Unable to listen for shutdown signals: {}", &e);
+                }
+                Either::Right(_) => (),
+            }
+        });
+    }
+
+    /// Processes the message on a new task, releasing its in flight slot when done
+    fn spawn_message(
+        &self,
+        queue: QueueConsumer,
+        worker: Arc<ShareableWorker>,
+        message: SqsMessage,
+    ) {
+        let processor = self.clone();
+        self.stats.record_started();
+        tokio::spawn(async move {
+            debug!("process_messages: handling {:?}", &message);
+            let message_id = message.message_id.clone();
+            if let Err(e) = processor.process_message(&queue, &worker, message).await {
+                error!("Error processing message: {:?} error: {}", &message_id, &e);
+            }
+            processor.stats.record_finished();
+            processor.concurrency.release();
+        });
+    }
+
     /// Returns a future that will process one message
     /// The message will be passed to the worker.
-    async fn process_message(&self, m: SqsMessage) -> Result<(), ProcessorError> {
+    async fn process_message(
+        &self,
+        queue: &QueueConsumer,
+        worker: &Arc<ShareableWorker>,
+        m: SqsMessage,
+    ) -> Result<(), ProcessorError> {
         debug!("Process message called with: {:?}", &m);
-        let message = m.clone();
-        let delete_clone = m.clone();
-        let work_error_clone = m.clone();
-        let sqs_client_or_else = self.sqs_client.clone(); // clone for if there was an error processing messages
-        let sqs_client_and_then = self.sqs_client.clone(); // clone for handle_delete
-        let worker = self.worker.clone();
-        let worker_future = async { worker.process(message) };
-
-        if let Err(e) = worker_future.await {
-            trace!("Received work error: {:?}", &e);
-            handle_work_error(sqs_client_or_else, e.clone(), work_error_clone).await
+        let timeout = worker.timeout().or(self.message_timeout);
+        let cancellation = self.abandoned.child_token();
+        let heartbeat = match (&self.visibility_heartbeat, &m.receipt_handle) {
+            (Some(heartbeat), Some(receipt_handle)) => Some(
+                queue
+                    .sqs_client
+                    .heartbeat(receipt_handle.to_owned(), heartbeat.clone())
+                    .boxed(),
+            ),
+            _ => None,
+        };
+        // a blocking worker would hold up the heartbeat and timeout until it is done
+        let blocking = worker.is_blocking() && (heartbeat.is_some() || timeout.is_some());
+        let work = async {
+            // the message waits for the rate limit on its own task, with the heartbeat keeping
+            // it hidden, so messages of other keys are not held up behind it
+            if !self.wait_for_rate_limit(&m).await {
+                return None;
+            }
+            let context = MessageContext::new(
+                &m,
+                queue.sqs_client.clone(),
+                cancellation.clone(),
+                self.shutdown.clone(),
+                timeout.map(|timeout| Instant::now() + timeout),
+            );
+            Some(run_worker(Arc::clone(worker), m.clone(), context, timeout, blocking).await)
+        };
+        let result = match with_heartbeat(work.boxed(), heartbeat).await {
+            Some(result) => result,
+            None => {
+                self.release_messages(queue, vec![m]).await;
+                return Ok(());
+            }
+        };
+        match result {
+            Ok(disposition) => {
+                self.record_outcome(&m, is_failure(&disposition));
+                self.handle_disposition(queue, disposition, m).await
+            }
+            Err(Abandoned::Panicked(panic)) => {
+                self.record_outcome(&m, true);
+                error!(
+                    "Worker panicked processing message: {:?} panic: {}",
+                    &m.message_id, panic
+                );
+                self.stats.record_panic();
+                self.handle_disposition(queue, self.panic_disposition.clone(), m)
+                    .await
+            }
+            Err(Abandoned::TimedOut(timeout)) => {
+                self.record_outcome(&m, true);
+                cancellation.cancel();
+                self.stats.record_timeout();
+                let message_id = m.message_id.clone();
+                self.handle_disposition(queue, self.timeout_disposition.clone(), m)
+                    .await?;
+                Err(ProcessorError::MessageTimeout(message_id, timeout))
+            }
+        }
+    }
+
+    /// Returns a future that will process a whole batch of messages
+    /// The messages will be passed to the batch worker and each result handled individually.
+    async fn process_batch(
+        &self,
+        queue: &QueueConsumer,
+        worker: &Arc<ShareableBatchWorker>,
+        messages: Vec<SqsMessage>,
+    ) {
+        debug!("Process batch called with {} messages", messages.len());
+        let batch = messages.clone();
+        let result = catch_panic(|| worker.process_batch(batch)).await;
+        match &result {
+            Err(panic) => {
+                let message_ids = messages.iter().map(|m| &m.message_id).collect::<Vec<_>>();
+                error!(
+                    "Batch worker panicked processing messages: {:?} panic: {}",
+                    message_ids, panic
+                );
+                self.stats.record_panic();
+            }
+            Ok(dispositions) if dispositions.len() != messages.len() => error!(
+                "Batch worker returned {} dispositions for {} messages, unmatched messages will be redelivered",
+                dispositions.len(),
+                messages.len()
+            ),
+            Ok(_) => (),
+        }
+
+        let outcome = batch_outcome(messages, result, &self.panic_disposition);
+        for message in &outcome.unmatched {
+            self.circuit_released(message);
+        }
+        for (message, disposition, failed) in outcome.handled {
+            self.record_outcome(&message, failed);
+            let message_clone = message.clone();
+            if let Err(e) = self.handle_disposition(queue, disposition, message).await {
+                error!(
+                    "Error processing message: {:?} error: {}",
+                    &message_clone, &e
+                );
+            }
+        }
+    }
+
+    async fn handle_disposition(
+        &self,
+        queue: &QueueConsumer,
+        disposition: Disposition,
+        m: SqsMessage,
+    ) -> Result<(), ProcessorError> {
+        handle_disposition(
+            queue.sqs_client.clone(),
+            self.dead_letter_queue.clone(),
+            self.retry_delay,
+            disposition,
+            m,
+        )
+        .await
+    }
+}
+
+/// The back-off state of a receive loop
+struct ReceiveBackoff {
+    error: Backoff,
+    empty: Option<Backoff>,
+}
+
+impl ReceiveBackoff {
+    /// Returns the delay before the next receive, if any, after a successful receive
+    fn after_receive(&mut self, received: usize) -> Option<Duration> {
+        self.error.reset();
+        match self.empty.as_mut() {
+            Some(backoff) if received == 0 => Some(backoff.next_delay()),
+            Some(backoff) => {
+                backoff.reset();
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn after_error(&mut self) -> Duration {
+        self.error.next_delay()
+    }
+}
+
+/// What happens to each message of a processed batch
+#[derive(Debug, PartialEq)]
+struct BatchOutcome {
+    /// Each message with its Disposition and whether it counts as a failure
+    handled: Vec<(SqsMessage, Disposition, bool)>,
+    /// Messages the worker returned no Disposition for, they are redelivered once their
+    /// visibility timeout expires
+    unmatched: Vec<SqsMessage>,
+}
+
+/// Pairs the messages of a batch with the dispositions the worker returned in the same order.
+/// If the worker panicked every message gets the panic Disposition and counts as a failure
+fn batch_outcome(
+    messages: Vec<SqsMessage>,
+    result: Result<Vec<Disposition>, String>,
+    panic_disposition: &Disposition,
+) -> BatchOutcome {
+    let (dispositions, panicked) = match result {
+        Ok(dispositions) => (dispositions, false),
+        Err(_) => (vec![panic_disposition.clone(); messages.len()], true),
+    };
+    let mut dispositions = dispositions.into_iter();
+    let mut outcome = BatchOutcome {
+        handled: vec![],
+        unmatched: vec![],
+    };
+    for message in messages {
+        match dispositions.next() {
+            Some(disposition) => {
+                let failed = panicked || is_failure(&disposition);
+                outcome.handled.push((message, disposition, failed));
+            }
+            None => outcome.unmatched.push(message),
+        }
+    }
+    outcome
+}
+
+async fn shutdown_workers(queues: &[QueueConsumer]) {
+    for queue in queues {
+        queue.worker.on_shutdown().await;
+    }
+}
+
+/// Messages the worker asked to retry count as failures for the circuit breaker
+fn is_failure(disposition: &Disposition) -> bool {
+    match disposition {
+        Disposition::Retry(_) | Disposition::Requeue => true,
+        _ => false,
+    }
+}
+
+/// Reasons the processor gives up on a message before the worker returned a Disposition
+#[derive(Debug, PartialEq)]
+enum Abandoned {
+    Panicked(String),
+    TimedOut(Duration),
+}
+
+/// The number of messages to receive for the queue when `available` of `limit` in flight
+/// slots are free
+///
+/// Single messages each take a slot once they are received, so only as many are received as
+/// there are free slots. A batch takes a slot per message when it is processed and waits for
+/// them, so batches are as large as the receive settings and the in flight limit allow.
+fn receive_size(queue: &QueueConsumer, available: usize, limit: usize) -> usize {
+    match queue.worker {
+        WorkerKind::Single(_) => available.max(1),
+        WorkerKind::Batch(_) => queue.receive.max_messages.min(limit.max(1)),
+    }
+}
+
+/// Runs the worker on the message, abandoning the message if the worker panics or times out
+///
+/// A blocking worker is run on a blocking thread if blocking is set, so the futures polled
+/// alongside it keep running while it works.
+async fn run_worker(
+    worker: Arc<ShareableWorker>,
+    message: SqsMessage,
+    context: MessageContext,
+    timeout: Option<Duration>,
+    blocking: bool,
+) -> Result<Disposition, Abandoned> {
+    let processed = async move {
+        if blocking {
+            process_blocking(worker, message, context).await
         } else {
-            handle_delete(sqs_client_and_then, delete_clone).await
+            catch_panic(|| worker.process(message, context)).await
         }
     }
+    .map(|result| result.map_err(Abandoned::Panicked));
+    match timeout {
+        Some(timeout) => time::timeout(timeout, processed)
+            .await
+            .unwrap_or_else(|_| Err(Abandoned::TimedOut(timeout))),
+        None => processed.await,
+    }
 }
 
-fn build_sqs_client(mode: &Mode) -> SqsClient {
+/// Processes the message on a blocking thread, returning the panic message if the worker panics
+async fn process_blocking(
+    worker: Arc<ShareableWorker>,
+    message: SqsMessage,
+    context: MessageContext,
+) -> Result<Disposition, String> {
+    let processed = task::spawn_blocking(move || {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            executor::block_on(worker.process(message, context))
+        }))
+    });
+    match processed.await {
+        Ok(result) => result.map_err(panic_message),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Polls the heartbeat alongside the work until the work is done
+async fn with_heartbeat<'a, T>(work: BoxFuture<'a, T>, heartbeat: Option<BoxFuture<'a, ()>>) -> T {
+    match heartbeat {
+        Some(heartbeat) => match future::select(work, heartbeat).await {
+            Either::Left((result, _)) => result,
+            Either::Right((_, work)) => work.await,
+        },
+        None => work.await,
+    }
+}
+
+/// Runs the future created by f, catching any panic raised while creating or polling it
+async fn catch_panic<'a, T, F>(f: F) -> Result<T, String>
+where
+    F: FnOnce() -> BoxFuture<'a, T>,
+{
+    let future = panic::catch_unwind(AssertUnwindSafe(f)).map_err(panic_message)?;
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .map_err(panic_message)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// Resolves once the process receives SIGTERM or SIGINT
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<(), ProcessorError> {
+    use tokio::signal::unix::{self, SignalKind};
+
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    let terminated = async move {
+        terminate.recv().await;
+        Ok::<(), ProcessorError>(())
+    };
+    match future::select(terminated.boxed(), signal::ctrl_c().boxed()).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result.map_err(ProcessorError::from),
+    }
+}
+
+/// Resolves once the process receives ctrl-c
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<(), ProcessorError> {
+    signal::ctrl_c().await.map_err(ProcessorError::from)
+}
+
+pub(crate) fn build_sqs_client(mode: &Mode) -> SqsClient {
     match mode {
         Mode::AWS(region, queue) => SqsClient::new(region.to_owned(), queue),
         Mode::Local(port, queue) => SqsClient::local(*port, queue),
     }
 }
 
+async fn handle_disposition(
+    sqs_client: SqsClient,
+    dead_letter_queue: Option<QueueURL>,
+    retry_delay: Duration,
+    disposition: Disposition,
+    m: SqsMessage,
+) -> Result<(), ProcessorError> {
+    trace!(
+        "Received disposition: {:?} for message: {:?}",
+        &disposition,
+        &m.message_id
+    );
+    match disposition {
+        Disposition::Ack => handle_delete(sqs_client, m).await,
+        Disposition::Retry(delay) => handle_retry(sqs_client, m, delay).await,
+        Disposition::Requeue => handle_retry(sqs_client, m, retry_delay).await,
+        Disposition::DeadLetter(reason) => {
+            handle_dead_letter(sqs_client, dead_letter_queue, m, reason).await
+        }
+        Disposition::Release => {
+            handle_change_visibility(sqs_client, m, Duration::from_secs(0)).await
+        }
+        Disposition::Leave => Ok(()),
+        Disposition::ExtendVisibility(timeout) => {
+            handle_change_visibility(sqs_client, m, timeout).await
+        }
+    }
+}
+
 async fn handle_delete(sqs_client: SqsClient, message: SqsMessage) -> Result<(), ProcessorError> {
     if let Some(receipt_handle) = message.receipt_handle.clone() {
         sqs_client.delete_message(receipt_handle.as_ref()).await
@@ -118,24 +1224,383 @@ async fn handle_delete(sqs_client: SqsClient, message: SqsMessage) -> Result<(),
     }
 }
 
-async fn handle_requeue(sqs_client: SqsClient, message: SqsMessage) -> Result<(), ProcessorError> {
-    sqs_client.requeue(message, DEFAULT_REQUEUE_DELAY).await
+async fn handle_retry(
+    sqs_client: SqsClient,
+    m: SqsMessage,
+    delay: Duration,
+) -> Result<(), ProcessorError> {
+    error!("Retrying message: {:?} in {:?}", &m.message_id, &delay);
+    handle_change_visibility(sqs_client, m, delay).await
 }
 
-async fn handle_work_error(
+async fn handle_change_visibility(
     sqs_client: SqsClient,
-    we: WorkError,
-    m: SqsMessage,
+    message: SqsMessage,
+    timeout: Duration,
+) -> Result<(), ProcessorError> {
+    if let Some(receipt_handle) = message.receipt_handle.clone() {
+        sqs_client
+            .change_message_visibility(receipt_handle.as_ref(), timeout.as_secs() as i64)
+            .await
+    } else {
+        error!("No receipt id found for message: {:?}", message);
+        Ok(())
+    }
+}
+
+async fn handle_dead_letter(
+    sqs_client: SqsClient,
+    dead_letter_queue: Option<QueueURL>,
+    message: SqsMessage,
+    reason: String,
 ) -> Result<(), ProcessorError> {
-    let delete_clone = m.clone();
-    match we.clone() {
-        WorkError::UnRecoverableError(msg) => {
-            error!("No way to recover from error: {} deleting", &msg);
-            handle_delete(sqs_client, delete_clone).await
+    if let Some(queue_url) = dead_letter_queue {
+        error!(
+            "Dead lettering message: {:?} reason: {}",
+            &message.message_id, &reason
+        );
+        sqs_client
+            .dead_letter(queue_url.as_ref(), message.clone(), reason.as_ref())
+            .await?;
+    } else {
+        error!(
+            "No dead letter queue configured, deleting message: {:?} reason: {}",
+            &message.message_id, &reason
+        );
+    }
+    handle_delete(sqs_client, message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backoff::assert_between;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn sleeping_worker(duration: Duration) -> Arc<ShareableWorker> {
+        Arc::new(worker_fn(move |_message: SqsMessage| {
+            thread::sleep(duration);
+            Disposition::Ack
+        }))
+    }
+
+    struct AckBatch;
+
+    impl BatchWorker for AckBatch {
+        fn process_batch(&self, messages: Vec<SqsMessage>) -> BoxFuture<'_, Vec<Disposition>> {
+            future::ready(vec![Disposition::Ack; messages.len()]).boxed()
+        }
+    }
+
+    fn queue(worker: WorkerKind) -> QueueConsumer {
+        QueueConsumer {
+            sqs_client: build_sqs_client(&Config::default().mode),
+            receive: ReceiveSettings::default(),
+            worker,
+        }
+    }
+
+    type Log = Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// Worker that logs its lifecycle hooks
+    struct HookWorker {
+        name: &'static str,
+        log: Log,
+        fail_start: bool,
+    }
+
+    impl HookWorker {
+        fn new(name: &'static str, log: &Log) -> Self {
+            HookWorker {
+                name,
+                log: Arc::clone(log),
+                fail_start: false,
+            }
+        }
+
+        fn record(&self, hook: &str) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.name, hook));
+        }
+    }
+
+    impl AsyncWorker for HookWorker {
+        fn process(
+            &self,
+            _message: SqsMessage,
+            _context: MessageContext,
+        ) -> BoxFuture<'_, Disposition> {
+            future::ready(Disposition::Ack).boxed()
+        }
+
+        fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+            self.record("start");
+            let result = if self.fail_start {
+                Err(WorkError::UnRecoverableError("not ready".to_owned()))
+            } else {
+                Ok(())
+            };
+            future::ready(result).boxed()
+        }
+
+        fn on_idle(&self) -> BoxFuture<'_, ()> {
+            self.record("idle");
+            future::ready(()).boxed()
+        }
+
+        fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+            self.record("shutdown");
+            future::ready(()).boxed()
+        }
+    }
+
+    fn hook(log: &Log, entry: &'static str) -> impl Fn() + Send + Sync + 'static {
+        let log = Arc::clone(log);
+        move || log.lock().unwrap().push(entry.to_owned())
+    }
+
+    fn entries(log: &Log) -> Vec<String> {
+        log.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_start_failure_shuts_down_started_workers() {
+        let log = Log::default();
+        let failing = HookWorker {
+            fail_start: true,
+            ..HookWorker::new("second", &log)
+        };
+        let processor = Processor::builder(Config::default())
+            .worker(HookWorker::new("first", &log))
+            .queue(
+                Mode::Local(9324, "other-queue".to_owned()),
+                ReceiveSettings::default(),
+                failing,
+            )
+            .on_start(hook(&log, "processor start"))
+            .on_shutdown(hook(&log, "processor shutdown"))
+            .build()
+            .unwrap();
+        match runtime().block_on(processor.process()) {
+            Err(ProcessorError::WorkerStartFailed(_)) => (),
+            other => panic!("expected WorkerStartFailed, got {:?}", other),
         }
-        WorkError::RecoverableError(msg) => {
-            error!("Recoverable from error: {} requeing", &msg);
-            handle_requeue(sqs_client, delete_clone).await
+        assert_eq!(
+            vec!["first start", "second start", "first shutdown"],
+            entries(&log)
+        );
+    }
+
+    #[test]
+    fn test_lifecycle_order() {
+        let log = Log::default();
+        let processor = Processor::builder(Config::default())
+            .worker(HookWorker::new("worker", &log))
+            .on_start(hook(&log, "processor start"))
+            .on_idle(hook(&log, "processor idle"))
+            .on_shutdown(hook(&log, "processor shutdown"))
+            .build()
+            .unwrap();
+        let queue = processor.queues[0].clone();
+        let mut runtime = runtime();
+        let mut idle = false;
+        runtime.block_on(processor.update_idle(&queue, &mut idle, 0));
+        runtime.block_on(processor.update_idle(&queue, &mut idle, 0));
+        runtime.block_on(processor.update_idle(&queue, &mut idle, 2));
+        runtime.block_on(processor.update_idle(&queue, &mut idle, 0));
+        // with shutdown already requested, process starts and stops without receiving
+        processor.shutdown();
+        assert!(runtime.block_on(processor.process()).is_ok());
+        assert_eq!(
+            vec![
+                "worker idle",
+                "processor idle",
+                "worker idle",
+                "processor idle",
+                "worker start",
+                "processor start",
+                "worker shutdown",
+                "processor shutdown",
+            ],
+            entries(&log)
+        );
+    }
+
+    fn receive_backoff(empty: Option<BackoffConfig>) -> ReceiveBackoff {
+        let config = BackoffConfig::new(Duration::from_millis(100), Duration::from_millis(1000));
+        ReceiveBackoff {
+            error: Backoff::new(config),
+            empty: empty.map(Backoff::new),
         }
     }
+
+    #[test]
+    fn test_error_backoff_resets_after_receive() {
+        let mut backoff = receive_backoff(None);
+        assert_between(backoff.after_error(), 50, 100);
+        assert_between(backoff.after_error(), 100, 200);
+        assert_eq!(None, backoff.after_receive(0));
+        assert_between(backoff.after_error(), 50, 100);
+    }
+
+    #[test]
+    fn test_empty_backoff() {
+        let empty = BackoffConfig::new(Duration::from_millis(10), Duration::from_millis(20));
+        let mut backoff = receive_backoff(Some(empty));
+        assert_between(backoff.after_receive(0).unwrap(), 5, 10);
+        assert_between(backoff.after_receive(0).unwrap(), 10, 20);
+        assert_between(backoff.after_receive(0).unwrap(), 10, 20);
+        assert_eq!(None, backoff.after_receive(3));
+        assert_between(backoff.after_receive(0).unwrap(), 5, 10);
+    }
+
+    #[test]
+    fn test_no_empty_backoff_after_long_poll() {
+        let worker = worker_fn(|_message: SqsMessage| Disposition::Ack);
+        let processor = Processor::builder(Config::default())
+            .worker(worker)
+            .build()
+            .unwrap();
+        assert_eq!(None, processor.receive_backoff(true).after_receive(0));
+        assert!(processor.receive_backoff(false).after_receive(0).is_some());
+    }
+
+    #[test]
+    fn test_receive_size() {
+        let single = queue(WorkerKind::Single(sleeping_worker(Duration::from_secs(0))));
+        assert_eq!(1, receive_size(&single, 0, 4));
+        assert_eq!(3, receive_size(&single, 3, 4));
+        let batch = queue(WorkerKind::Batch(Arc::new(AckBatch)));
+        assert_eq!(10, receive_size(&batch, 0, 32));
+        assert_eq!(4, receive_size(&batch, 0, 4));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let ok = executor::block_on(catch_panic(|| future::ready(1).boxed()));
+        assert_eq!(Ok(1), ok);
+        let polled: Result<(), String> =
+            executor::block_on(catch_panic(|| async { panic!("polled") }.boxed()));
+        assert_eq!(Err("polled".to_owned()), polled);
+        let created: Result<(), String> =
+            executor::block_on(catch_panic(|| -> BoxFuture<'static, ()> {
+                panic!("created {}", 1)
+            }));
+        assert_eq!(Err("created 1".to_owned()), created);
+    }
+
+    #[test]
+    fn test_is_failure() {
+        assert!(is_failure(&Disposition::Retry(Duration::from_secs(1))));
+        assert!(is_failure(&Disposition::Requeue));
+        assert!(!is_failure(&Disposition::Ack));
+        assert!(!is_failure(&Disposition::DeadLetter("reason".to_owned())));
+        assert!(!is_failure(&Disposition::Release));
+        assert!(!is_failure(&Disposition::Leave));
+        assert!(!is_failure(&Disposition::ExtendVisibility(
+            Duration::from_secs(1)
+        )));
+    }
+
+    fn message(id: &str) -> SqsMessage {
+        SqsMessage {
+            message_id: Some(id.to_owned()),
+            ..SqsMessage::default()
+        }
+    }
+
+    #[test]
+    fn test_batch_partial_failure() {
+        let messages = vec![message("1"), message("2"), message("3")];
+        let dispositions = vec![
+            Disposition::Ack,
+            Disposition::Retry(Duration::from_secs(5)),
+            Disposition::DeadLetter("bad".to_owned()),
+        ];
+        let outcome = batch_outcome(messages, Ok(dispositions), &Disposition::Leave);
+        assert_eq!(
+            vec![
+                (message("1"), Disposition::Ack, false),
+                (
+                    message("2"),
+                    Disposition::Retry(Duration::from_secs(5)),
+                    true
+                ),
+                (
+                    message("3"),
+                    Disposition::DeadLetter("bad".to_owned()),
+                    false
+                ),
+            ],
+            outcome.handled
+        );
+        assert!(outcome.unmatched.is_empty());
+    }
+
+    #[test]
+    fn test_batch_panic() {
+        let messages = vec![message("1"), message("2")];
+        let outcome = batch_outcome(messages, Err("boom".to_owned()), &Disposition::Release);
+        assert_eq!(
+            vec![
+                (message("1"), Disposition::Release, true),
+                (message("2"), Disposition::Release, true),
+            ],
+            outcome.handled
+        );
+    }
+
+    #[test]
+    fn test_batch_too_few_dispositions() {
+        let messages = vec![message("1"), message("2"), message("3")];
+        let outcome = batch_outcome(messages, Ok(vec![Disposition::Ack]), &Disposition::Leave);
+        assert_eq!(
+            vec![(message("1"), Disposition::Ack, false)],
+            outcome.handled
+        );
+        assert_eq!(vec![message("2"), message("3")], outcome.unmatched);
+    }
+
+    #[test]
+    fn test_heartbeat_while_sync_worker_blocks() {
+        let beats = Arc::new(AtomicUsize::new(0));
+        let heartbeat = {
+            let beats = Arc::clone(&beats);
+            async move {
+                let mut interval = time::interval(Duration::from_millis(50));
+                loop {
+                    interval.tick().await;
+                    beats.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        };
+        let worker = sleeping_worker(Duration::from_millis(300));
+        let message = SqsMessage::default();
+        let context = MessageContext::for_test(&message);
+        let work = run_worker(worker, message, context, None, true);
+        let result = runtime().block_on(with_heartbeat(work.boxed(), Some(heartbeat.boxed())));
+        assert_eq!(Ok(Disposition::Ack), result);
+        assert!(beats.load(Ordering::SeqCst) >= 3);
+    }
+
+    #[test]
+    fn test_sync_worker_times_out() {
+        let timeout = Duration::from_millis(50);
+        let worker = sleeping_worker(Duration::from_millis(300));
+        let message = SqsMessage::default();
+        let context = MessageContext::for_test(&message);
+        let work = run_worker(worker, message, context, Some(timeout), true);
+        assert_eq!(Err(Abandoned::TimedOut(timeout)), runtime().block_on(work));
+    }
 }
@@ -0,0 +1,266 @@
+use crate::router::{routing_key, RouteKey};
+use rusoto_sqs::Message as SqsMessage;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keyed buckets are pruned of idle keys once there are more than this many
+const MAX_IDLE_KEYS: usize = 1024;
+
+/// A token bucket rate
+#[derive(Debug, PartialEq, Clone)]
+pub struct RateLimit {
+    /// The number of messages allowed per second
+    pub per_second: f64,
+    /// The number of messages allowed at once after a quiet period
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allows per_second messages a second, with a burst of one second worth of messages
+    pub fn per_second(per_second: f64) -> Self {
+        RateLimit {
+            per_second,
+            burst: per_second.ceil().max(1.0) as u32,
+        }
+    }
+
+    pub fn with_burst(self, burst: u32) -> Self {
+        RateLimit { burst, ..self }
+    }
+}
+
+/// Limits how fast messages are received and handed to workers
+///
+/// A message over the limit waits for it on its own task, holding its in flight slot.
+///
+/// let rate_limit = RateLimitConfig::global(RateLimit::per_second(100.0))
+///     .with_per_key(RouteKey::Attribute("customer".to_owned()), RateLimit::per_second(5.0));
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RateLimitConfig {
+    /// The limit on all messages
+    pub global: Option<RateLimit>,
+    /// The limit on the messages of each key. Messages without the key only count against
+    /// the global limit
+    pub per_key: Option<(RouteKey, RateLimit)>,
+}
+
+impl RateLimitConfig {
+    pub fn global(limit: RateLimit) -> Self {
+        RateLimitConfig {
+            global: Some(limit),
+            per_key: None,
+        }
+    }
+
+    pub fn per_key(key: RouteKey, limit: RateLimit) -> Self {
+        RateLimitConfig {
+            global: None,
+            per_key: Some((key, limit)),
+        }
+    }
+
+    pub fn with_per_key(self, key: RouteKey, limit: RateLimit) -> Self {
+        RateLimitConfig {
+            per_key: Some((key, limit)),
+            ..self
+        }
+    }
+
+    fn limits(&self) -> impl Iterator<Item = &RateLimit> {
+        self.global
+            .iter()
+            .chain(self.per_key.iter().map(|(_, limit)| limit))
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.limits()
+            .all(|limit| limit.per_second > 0.0 && limit.burst > 0)
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(limit.burst),
+            limit,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let tokens = self.tokens + elapsed.as_secs_f64() * self.limit.per_second;
+        self.tokens = tokens.min(f64::from(self.limit.burst));
+        self.updated = now;
+    }
+
+    /// How long until a token is available
+    fn wait(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.limit.per_second)
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= f64::from(self.limit.burst)
+    }
+}
+
+/// Token buckets shared by everything a processor receives
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_key: Option<(RouteKey, RateLimit)>,
+    keyed: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig, now: Instant) -> Self {
+        RateLimiter {
+            global: config
+                .global
+                .map(|limit| Mutex::new(TokenBucket::new(limit, now))),
+            per_key: config.per_key,
+            keyed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The number of messages the global limit allows right now, or how long until it allows one
+    pub(crate) fn available(&self, now: Instant) -> Result<usize, Duration> {
+        match &self.global {
+            Some(global) => {
+                let mut global = global.lock().unwrap();
+                global.refill(now);
+                if global.tokens >= 1.0 {
+                    Ok(global.tokens as usize)
+                } else {
+                    Err(global.wait())
+                }
+            }
+            None => Ok(usize::max_value()),
+        }
+    }
+
+    /// Takes a token for the message from every limit that applies to it, or returns how
+    /// long to wait before trying again. No tokens are taken unless every limit allows it
+    pub(crate) fn try_acquire(&self, message: &SqsMessage, now: Instant) -> Result<(), Duration> {
+        let mut global = self.global.as_ref().map(|global| global.lock().unwrap());
+        let mut keyed = self.keyed.lock().unwrap();
+        let key = self
+            .per_key
+            .as_ref()
+            .and_then(|(key, limit)| routing_key(key, message).map(|value| (value, limit)));
+
+        let mut wait = Duration::from_secs(0);
+        if let Some(global) = global.as_mut() {
+            global.refill(now);
+            wait = wait.max(global.wait());
+        }
+        if let Some((value, limit)) = &key {
+            if keyed.len() > MAX_IDLE_KEYS {
+                keyed.retain(|_, bucket| {
+                    bucket.refill(now);
+                    !bucket.is_full()
+                });
+            }
+            let bucket = keyed
+                .entry(value.clone())
+                .or_insert_with(|| TokenBucket::new((*limit).clone(), now));
+            bucket.refill(now);
+            wait = wait.max(bucket.wait());
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        if let Some(global) = global.as_mut() {
+            global.tokens -= 1.0;
+        }
+        if let Some((value, _)) = key {
+            if let Some(bucket) = keyed.get_mut(&value) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_sqs::MessageAttributeValue;
+
+    fn with_customer(customer: &str) -> SqsMessage {
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            "customer".to_owned(),
+            MessageAttributeValue {
+                data_type: "String".to_owned(),
+                string_value: Some(customer.to_owned()),
+                ..MessageAttributeValue::default()
+            },
+        );
+        SqsMessage {
+            message_attributes: Some(attributes),
+            ..SqsMessage::default()
+        }
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(RateLimitConfig::global(RateLimit::per_second(2.0)), now);
+        let message = SqsMessage::default();
+        assert_eq!(Ok(2), limiter.available(now));
+        assert!(limiter.try_acquire(&message, now).is_ok());
+        assert!(limiter.try_acquire(&message, now).is_ok());
+        assert_eq!(
+            Err(Duration::from_millis(500)),
+            limiter.try_acquire(&message, now)
+        );
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.try_acquire(&message, later).is_ok());
+    }
+
+    #[test]
+    fn test_per_key_limit() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(
+            RateLimitConfig::per_key(
+                RouteKey::Attribute("customer".to_owned()),
+                RateLimit::per_second(1.0),
+            ),
+            now,
+        );
+        assert!(limiter.try_acquire(&with_customer("a"), now).is_ok());
+        assert!(limiter.try_acquire(&with_customer("a"), now).is_err());
+        assert!(limiter.try_acquire(&with_customer("b"), now).is_ok());
+        assert!(limiter.try_acquire(&SqsMessage::default(), now).is_ok());
+    }
+
+    #[test]
+    fn test_no_tokens_taken_when_limited() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(
+            RateLimitConfig::global(RateLimit::per_second(1.0)).with_per_key(
+                RouteKey::Attribute("customer".to_owned()),
+                RateLimit::per_second(1.0),
+            ),
+            now,
+        );
+        assert!(limiter.try_acquire(&with_customer("a"), now).is_ok());
+        assert!(limiter.try_acquire(&with_customer("b"), now).is_err());
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.try_acquire(&with_customer("b"), later).is_ok());
+    }
+}
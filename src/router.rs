@@ -0,0 +1,402 @@
+use crate::context::MessageContext;
+use crate::disposition::Disposition;
+use crate::errors::WorkError;
+use crate::work::AsyncWorker;
+use futures::future::{self, BoxFuture, FutureExt};
+use log::{debug, error};
+use rusoto_sqs::Message as SqsMessage;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time;
+
+type RouteWorker = Box<dyn AsyncWorker + Send + Sync>;
+type Predicate = Box<dyn Fn(&SqsMessage) -> bool + Send + Sync>;
+
+/// Where the routing key of a message is read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteKey {
+    /// Only predicate routes are used
+    None,
+    /// The string value of the message attribute with the specified name
+    Attribute(String),
+    /// The value of the specified field of the json body.
+    /// Nested fields can be selected with a json pointer such as /header/type
+    JsonField(String),
+}
+
+/// Worker that dispatches each message to one of several workers
+///
+/// Predicate routes are checked first in the order they were added, then the routing key
+/// of the message is looked up. Messages that match no route are handed to the fallback
+/// worker, or given the unroutable Disposition if there is no fallback.
+///
+/// The processor applies the timeout of the router, set with with_timeout. A route worker that
+/// sets its own timeout is given the timeout Disposition of the router when it does not finish
+/// in time, and the cancellation token of the message is cancelled.
+///
+/// let router = Router::by_attribute("type")
+///     .route("order", OrderWorker::default())
+///     .route("refund", RefundWorker::default())
+///     .fallback(LoggingWorker::default());
+pub struct Router {
+    key: RouteKey,
+    routes: HashMap<String, RouteWorker>,
+    predicates: Vec<(Predicate, RouteWorker)>,
+    fallback: Option<RouteWorker>,
+    unroutable: Disposition,
+    timeout: Option<Duration>,
+    timeout_disposition: Disposition,
+}
+
+impl Router {
+    /// Creates a router that only dispatches with predicate routes
+    pub fn new() -> Self {
+        Router::with_key(RouteKey::None)
+    }
+
+    /// Creates a router that dispatches on the value of a message attribute
+    pub fn by_attribute(name: &str) -> Self {
+        Router::with_key(RouteKey::Attribute(name.to_owned()))
+    }
+
+    /// Creates a router that dispatches on the value of a field of the json body
+    pub fn by_json_field(field: &str) -> Self {
+        Router::with_key(RouteKey::JsonField(field.to_owned()))
+    }
+
+    pub fn with_key(key: RouteKey) -> Self {
+        Router {
+            key,
+            routes: HashMap::new(),
+            predicates: vec![],
+            fallback: None,
+            unroutable: Disposition::DeadLetter("No route found for message".to_owned()),
+            timeout: None,
+            timeout_disposition: Disposition::Release,
+        }
+    }
+
+    /// Routes messages with the specified routing key to the worker
+    pub fn route<W>(mut self, value: &str, worker: W) -> Self
+    where
+        W: AsyncWorker + Send + Sync + 'static,
+    {
+        self.routes.insert(value.to_owned(), Box::new(worker));
+        self
+    }
+
+    /// Routes messages the predicate returns true for to the worker
+    pub fn route_when<P, W>(mut self, predicate: P, worker: W) -> Self
+    where
+        P: Fn(&SqsMessage) -> bool + Send + Sync + 'static,
+        W: AsyncWorker + Send + Sync + 'static,
+    {
+        self.predicates
+            .push((Box::new(predicate), Box::new(worker)));
+        self
+    }
+
+    /// Handles messages that match no route
+    pub fn fallback<W>(self, worker: W) -> Self
+    where
+        W: AsyncWorker + Send + Sync + 'static,
+    {
+        Router {
+            fallback: Some(Box::new(worker)),
+            ..self
+        }
+    }
+
+    /// Sets the Disposition of messages that match no route when there is no fallback
+    pub fn with_unroutable(self, unroutable: Disposition) -> Self {
+        Router { unroutable, ..self }
+    }
+
+    /// Overrides the message timeout of the processor for every message the router handles
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Router {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Sets the Disposition of messages whose route worker times out, Release by default
+    pub fn with_timeout_disposition(self, timeout_disposition: Disposition) -> Self {
+        Router {
+            timeout_disposition,
+            ..self
+        }
+    }
+
+    fn find_worker(&self, message: &SqsMessage) -> Option<&RouteWorker> {
+        self.predicates
+            .iter()
+            .find(|(predicate, _)| predicate(message))
+            .map(|(_, worker)| worker)
+            .or_else(|| {
+                routing_key(&self.key, message).and_then(|key| {
+                    debug!("Routing message: {:?} key: {}", &message.message_id, &key);
+                    self.routes.get(&key)
+                })
+            })
+            .or_else(|| self.fallback.as_ref())
+    }
+
+    fn workers(&self) -> impl Iterator<Item = &RouteWorker> {
+        self.predicates
+            .iter()
+            .map(|(_, worker)| worker)
+            .chain(self.routes.values())
+            .chain(self.fallback.iter())
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl AsyncWorker for Router {
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition> {
+        match self.find_worker(&message) {
+            Some(worker) => match worker.timeout() {
+                Some(timeout) => {
+                    let message_id = message.message_id.clone();
+                    let cancellation = context.cancellation().clone();
+                    let timeout_disposition = self.timeout_disposition.clone();
+                    time::timeout(timeout, worker.process(message, context))
+                        .map(move |result| {
+                            result.unwrap_or_else(|_| {
+                                error!(
+                                    "Route timed out processing message: {:?} after {:?}",
+                                    &message_id, timeout
+                                );
+                                cancellation.cancel();
+                                timeout_disposition
+                            })
+                        })
+                        .boxed()
+                }
+                None => worker.process(message, context),
+            },
+            None => {
+                error!("No route found for message: {:?}", &message.message_id);
+                future::ready(self.unroutable.clone()).boxed()
+            }
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// A router is run on a blocking thread if any of its workers blocks
+    fn is_blocking(&self) -> bool {
+        self.workers().any(|worker| worker.is_blocking())
+    }
+
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        async move {
+            for worker in self.workers() {
+                if let Err(e) = worker.on_start().await {
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        future::join_all(self.workers().map(|worker| worker.on_idle()))
+            .map(|_| ())
+            .boxed()
+    }
+
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        future::join_all(self.workers().map(|worker| worker.on_shutdown()))
+            .map(|_| ())
+            .boxed()
+    }
+}
+
+pub(crate) fn routing_key(key: &RouteKey, message: &SqsMessage) -> Option<String> {
+    match key {
+        RouteKey::None => None,
+        RouteKey::Attribute(name) => message
+            .message_attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get(name))
+            .and_then(|attribute| attribute.string_value.clone()),
+        RouteKey::JsonField(field) => message
+            .body
+            .as_ref()
+            .and_then(|body| serde_json::from_str::<Value>(body).ok())
+            .and_then(|json| {
+                if field.starts_with('/') {
+                    json.pointer(field).cloned()
+                } else {
+                    json.get(field).cloned()
+                }
+            })
+            .map(|value| match value {
+                Value::String(s) => s,
+                other => other.to_string(),
+            }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::process_for_test;
+    use crate::work::Worker;
+    use rusoto_sqs::MessageAttributeValue;
+
+    struct ConstantWorker(Disposition);
+
+    impl Worker for ConstantWorker {
+        fn process(&self, _message: SqsMessage, _context: MessageContext) -> Disposition {
+            self.0.clone()
+        }
+    }
+
+    fn with_attribute(name: &str, value: &str) -> SqsMessage {
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            name.to_owned(),
+            MessageAttributeValue {
+                data_type: "String".to_owned(),
+                string_value: Some(value.to_owned()),
+                ..MessageAttributeValue::default()
+            },
+        );
+        SqsMessage {
+            message_attributes: Some(attributes),
+            ..SqsMessage::default()
+        }
+    }
+
+    fn with_body(body: &str) -> SqsMessage {
+        SqsMessage {
+            body: Some(body.to_owned()),
+            ..SqsMessage::default()
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let router = Router::new().route_when(|_| true, ConstantWorker(Disposition::Ack));
+        assert_eq!(None, router.timeout());
+        let router = router.with_timeout(Duration::from_secs(5));
+        assert_eq!(Some(Duration::from_secs(5)), router.timeout());
+    }
+
+    struct SlowWorker;
+
+    impl AsyncWorker for SlowWorker {
+        fn process(
+            &self,
+            _message: SqsMessage,
+            _context: MessageContext,
+        ) -> BoxFuture<'_, Disposition> {
+            time::delay_for(Duration::from_secs(5))
+                .map(|_| Disposition::Ack)
+                .boxed()
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+    }
+
+    #[test]
+    fn test_route_timeout() {
+        let router = Router::by_attribute("type")
+            .route("slow", SlowWorker)
+            .with_timeout_disposition(Disposition::Leave);
+        let message = with_attribute("type", "slow");
+        let context = MessageContext::for_test(&message);
+        let cancellation = context.cancellation().clone();
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let disposition = runtime.block_on(AsyncWorker::process(&router, message, context));
+        assert_eq!(Disposition::Leave, disposition);
+        assert!(cancellation.is_cancelled());
+    }
+
+    #[test]
+    fn test_route_by_attribute() {
+        let router = Router::by_attribute("type")
+            .route("ack", ConstantWorker(Disposition::Ack))
+            .route("leave", ConstantWorker(Disposition::Leave));
+        assert_eq!(
+            Disposition::Ack,
+            process_for_test(&router, with_attribute("type", "ack"))
+        );
+        assert_eq!(
+            Disposition::Leave,
+            process_for_test(&router, with_attribute("type", "leave"))
+        );
+    }
+
+    #[test]
+    fn test_route_by_json_field() {
+        let router = Router::by_json_field("/header/type")
+            .route("ack", ConstantWorker(Disposition::Ack))
+            .route("1", ConstantWorker(Disposition::Leave));
+        assert_eq!(
+            Disposition::Ack,
+            process_for_test(&router, with_body(r#"{"header": {"type": "ack"}}"#))
+        );
+        assert_eq!(
+            Disposition::Leave,
+            process_for_test(&router, with_body(r#"{"header": {"type": 1}}"#))
+        );
+    }
+
+    #[test]
+    fn test_predicate_before_key() {
+        let router = Router::by_attribute("type")
+            .route("ack", ConstantWorker(Disposition::Ack))
+            .route_when(
+                |m| m.message_attributes.is_some(),
+                ConstantWorker(Disposition::Release),
+            );
+        assert_eq!(
+            Disposition::Release,
+            process_for_test(&router, with_attribute("type", "ack"))
+        );
+    }
+
+    #[test]
+    fn test_fallback() {
+        let router = Router::by_json_field("type")
+            .route("ack", ConstantWorker(Disposition::Ack))
+            .fallback(ConstantWorker(Disposition::Leave));
+        assert_eq!(
+            Disposition::Leave,
+            process_for_test(&router, with_body(r#"{"type": "other"}"#))
+        );
+        assert_eq!(
+            Disposition::Leave,
+            process_for_test(&router, with_body("not json"))
+        );
+    }
+
+    #[test]
+    fn test_unroutable() {
+        let router = Router::by_attribute("type")
+            .route("ack", ConstantWorker(Disposition::Ack))
+            .with_unroutable(Disposition::Release);
+        assert_eq!(
+            Disposition::Release,
+            process_for_test(&router, with_attribute("kind", "ack"))
+        );
+    }
+}
@@ -1,17 +1,26 @@
+use crate::config::{ReceiveSettings, VisibilityHeartbeat};
 use crate::errors::ProcessorError;
 use futures::compat::Future01CompatExt;
-use log::{debug, trace};
+use log::{debug, error, trace};
 use rusoto_core::HttpClient;
 use rusoto_core::Region;
 use rusoto_credential::StaticProvider;
 use rusoto_sqs::{
-    DeleteMessageRequest, Message as SqsMessage, ReceiveMessageRequest, SendMessageRequest, Sqs,
+    ChangeMessageVisibilityRequest, DeleteMessageRequest, Message as SqsMessage,
+    MessageAttributeValue, ReceiveMessageRequest, SendMessageRequest, Sqs,
     SqsClient as RusotoSqsClient,
 };
 use std::convert::From;
 use std::sync::Arc;
+use tokio::time::{self, Instant};
 
 const SQS_LOCAL_REGION: &'static str = "sqs-local";
+/// The maximum number of messages SQS returns from one receive
+pub(crate) const MAX_RECEIVE_MESSAGES: usize = 10;
+/// The longest SQS lets a receive wait for messages
+const MAX_WAIT_TIME_SECONDS: u64 = 20;
+/// Message attribute containing the reason a message was dead lettered
+const DEAD_LETTER_REASON_ATTRIBUTE: &'static str = "DeadLetterReason";
 
 #[derive(Clone)]
 pub struct SqsClient {
@@ -28,15 +37,40 @@ impl SqsClient {
         }
     }
 
+    /// Uses an already configured rusoto client
+    pub fn with_client(sqs: RusotoSqsClient, queue_url: &str) -> Self {
+        SqsClient {
+            queue_url: queue_url.to_owned(),
+            sqs: Arc::new(sqs),
+        }
+    }
+
     pub fn local(port: u32, queue_url: &str) -> Self {
         SqsClient::new(build_local_region(port), queue_url)
     }
 
-    pub async fn fetch_messages(&self) -> Result<Vec<SqsMessage>, ProcessorError> {
+    /// Receives up to max_messages messages, further limited by the receive settings
+    pub async fn fetch_messages(
+        &self,
+        settings: &ReceiveSettings,
+        max_messages: usize,
+    ) -> Result<Vec<SqsMessage>, ProcessorError> {
         trace!("fetch_messages called");
+        let max_messages = max_messages
+            .min(settings.max_messages)
+            .min(MAX_RECEIVE_MESSAGES)
+            .max(1);
         let mut request = ReceiveMessageRequest::default();
-        request.max_number_of_messages = Some(10);
+        request.max_number_of_messages = Some(max_messages as i64);
         request.queue_url = self.queue_url.clone();
+        request.wait_time_seconds = settings
+            .wait_time
+            .map(|wait_time| wait_time.as_secs().min(MAX_WAIT_TIME_SECONDS) as i64);
+        request.visibility_timeout = settings
+            .visibility_timeout
+            .map(|visibility_timeout| visibility_timeout.as_secs() as i64);
+        request.attribute_names = Some(settings.attribute_names.clone());
+        request.message_attribute_names = Some(settings.message_attribute_names.clone());
 
         self.sqs
             .receive_message(request)
@@ -64,15 +98,92 @@ impl SqsClient {
             .map_err(ProcessorError::from)
     }
 
-    pub async fn requeue(
+    pub async fn change_message_visibility(
         &self,
+        receipt_handle: &str,
+        visibility_timeout: i64,
+    ) -> Result<(), ProcessorError> {
+        debug!(
+            "change_message_visibility called. receipt_handle: {} visibility_timeout: {}",
+            receipt_handle, visibility_timeout
+        );
+        let mut request = ChangeMessageVisibilityRequest::default();
+        request.queue_url = self.queue_url.clone();
+        request.receipt_handle = receipt_handle.to_owned();
+        request.visibility_timeout = visibility_timeout;
+
+        self.sqs
+            .change_message_visibility(request)
+            .compat()
+            .await
+            .map_err(ProcessorError::from)
+    }
+
+    /// Periodically extends the visibility timeout of a message until the maximum extension
+    /// is reached. The returned future is meant to be dropped once the message is processed.
+    pub async fn heartbeat(&self, receipt_handle: String, heartbeat: VisibilityHeartbeat) {
+        let started = Instant::now();
+        let mut interval = time::interval_at(started + heartbeat.interval, heartbeat.interval);
+        loop {
+            interval.tick().await;
+            let remaining = heartbeat
+                .max_extension
+                .checked_sub(started.elapsed())
+                .unwrap_or_default();
+            if remaining.as_secs() == 0 {
+                debug!(
+                    "Maximum visibility extension reached for: {}",
+                    &receipt_handle
+                );
+                break;
+            }
+
+            let visibility_timeout = heartbeat.visibility_timeout.min(remaining);
+            trace!(
+                "Sending heartbeat for: {} visibility_timeout: {:?}",
+                &receipt_handle,
+                &visibility_timeout
+            );
+            if let Err(e) = self
+                .change_message_visibility(
+                    receipt_handle.as_ref(),
+                    visibility_timeout.as_secs() as i64,
+                )
+                .await
+            {
+                error!(
+                    "Error sending heartbeat for: {} error: {}",
+                    &receipt_handle, e
+                );
+            }
+        }
+    }
+
+    /// Sends a copy of the message, including its attributes, to the dead letter queue
+    pub async fn dead_letter(
+        &self,
+        dead_letter_queue_url: &str,
         message: SqsMessage,
-        delay_seconds: i64,
+        reason: &str,
     ) -> Result<(), ProcessorError> {
+        debug!(
+            "dead_letter called. message_id: {:?} reason: {}",
+            &message.message_id, reason
+        );
+        let mut attributes = message.message_attributes.unwrap_or_default();
+        attributes.insert(
+            DEAD_LETTER_REASON_ATTRIBUTE.to_owned(),
+            MessageAttributeValue {
+                data_type: "String".to_owned(),
+                string_value: Some(reason.to_owned()),
+                ..MessageAttributeValue::default()
+            },
+        );
+
         let mut request = SendMessageRequest::default();
-        request.queue_url = self.queue_url.to_owned();
+        request.queue_url = dead_letter_queue_url.to_owned();
         request.message_body = message.body.unwrap_or("".to_owned());
-        request.delay_seconds = Some(delay_seconds);
+        request.message_attributes = Some(attributes);
 
         self.sqs
             .send_message(request)
@@ -126,7 +237,10 @@ mod tests {
 
             let client = SqsClient::local(host_port.into(), queue_url.as_ref());
 
-            let result: Vec<SqsMessage> = client.fetch_messages().await.unwrap();
+            let result: Vec<SqsMessage> = client
+                .fetch_messages(&ReceiveSettings::default(), MAX_RECEIVE_MESSAGES)
+                .await
+                .unwrap();
 
             assert_eq!(1, result.len());
             let our_message: &SqsMessage = result.get(0).unwrap();
@@ -0,0 +1,60 @@
+use crate::circuit::CircuitState;
+use crate::pool::BlockingPoolStats;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Counters that are updated by the processor while it is running
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    panics: AtomicU64,
+    timeouts: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+impl Stats {
+    pub(crate) fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_finished(&self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            panics: self.panics.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            max_in_flight: 0,
+            paused: false,
+            blocking_pool: None,
+            circuit: None,
+        }
+    }
+}
+
+/// A point in time copy of the processor counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    /// The number of worker invocations that panicked
+    pub panics: u64,
+    /// The number of messages that were abandoned because they timed out
+    pub timeouts: u64,
+    /// The number of messages being processed
+    pub in_flight: usize,
+    /// The maximum number of messages processed at the same time
+    pub max_in_flight: usize,
+    /// Whether receiving messages is paused
+    pub paused: bool,
+    /// The blocking pool counters, if the processor was created with Processor::new_blocking
+    pub blocking_pool: Option<BlockingPoolStats>,
+    /// The state of the circuit breaker, if one is configured
+    pub circuit: Option<CircuitState>,
+}
@@ -0,0 +1,171 @@
+use crate::codec::{Codec, JsonCodec};
+use crate::context::MessageContext;
+use crate::disposition::Disposition;
+use crate::errors::CodecError;
+use crate::work::Worker;
+use log::debug;
+use rusoto_sqs::Message as SqsMessage;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+/// Trait to implement to handle an already decoded message payload
+pub trait TypedHandler<T> {
+    /// Handles the decoded payload. The Disposition is handled the same way as for Worker::process
+    fn handle(&self, payload: T) -> Disposition;
+}
+
+/// Functions returning a Disposition or a Result<(), WorkError> can be used as handlers
+impl<T, F, R> TypedHandler<T> for F
+where
+    F: Fn(T) -> R,
+    R: Into<Disposition>,
+{
+    fn handle(&self, payload: T) -> Disposition {
+        self(payload).into()
+    }
+}
+
+/// Worker that decodes the message body with a Codec before handing it to a TypedHandler
+///
+/// let worker = TypedWorker::new(|workload: WorkLoad| {
+///     println!("Received workload: {:?}", workload);
+///     Ok(())
+/// });
+pub struct TypedWorker<T, H, C = JsonCodec> {
+    handler: H,
+    codec: C,
+    on_decode_error: fn(CodecError) -> Disposition,
+    payload: PhantomData<fn() -> T>,
+}
+
+impl<T, H> TypedWorker<T, H, JsonCodec>
+where
+    T: DeserializeOwned,
+    H: TypedHandler<T>,
+{
+    /// Creates a TypedWorker that decodes json bodies.
+    /// Messages that cannot be decoded are dead lettered
+    pub fn new(handler: H) -> Self {
+        TypedWorker {
+            handler,
+            codec: JsonCodec,
+            on_decode_error: dead_letter,
+            payload: PhantomData,
+        }
+    }
+}
+
+impl<T, H, C> TypedWorker<T, H, C> {
+    /// Replaces the codec used to decode message bodies
+    pub fn with_codec<D: Codec<T>>(self, codec: D) -> TypedWorker<T, H, D> {
+        TypedWorker {
+            handler: self.handler,
+            codec,
+            on_decode_error: self.on_decode_error,
+            payload: PhantomData,
+        }
+    }
+
+    /// Sets the Disposition of messages that cannot be decoded, e.g. Disposition::Leave
+    pub fn with_decode_error(self, on_decode_error: fn(CodecError) -> Disposition) -> Self {
+        TypedWorker {
+            on_decode_error,
+            ..self
+        }
+    }
+}
+
+impl<T, H, C> Worker for TypedWorker<T, H, C>
+where
+    H: TypedHandler<T>,
+    C: Codec<T>,
+{
+    fn process(&self, message: SqsMessage, _context: MessageContext) -> Disposition {
+        self.decode_and_handle(message)
+    }
+}
+
+impl<T, H, C> TypedWorker<T, H, C>
+where
+    H: TypedHandler<T>,
+    C: Codec<T>,
+{
+    fn decode_and_handle(&self, message: SqsMessage) -> Disposition {
+        let decoded = message
+            .body
+            .ok_or(CodecError::MissingBody)
+            .and_then(|body| self.codec.decode(body.as_ref()));
+
+        match decoded {
+            Ok(payload) => self.handler.handle(payload),
+            Err(e) => {
+                debug!("Could not decode message body: {}", &e);
+                (self.on_decode_error)(e)
+            }
+        }
+    }
+}
+
+fn dead_letter(e: CodecError) -> Disposition {
+    Disposition::DeadLetter(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::WorkError;
+    use serde_derive::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Payload {
+        text: String,
+    }
+
+    fn message(body: Option<&str>) -> SqsMessage {
+        SqsMessage {
+            body: body.map(|b| b.to_owned()),
+            ..SqsMessage::default()
+        }
+    }
+
+    fn handler(payload: Payload) -> Result<(), WorkError> {
+        if payload.text == "Hello" {
+            Ok(())
+        } else {
+            Err(WorkError::RecoverableError(payload.text))
+        }
+    }
+
+    #[test]
+    fn test_decodes_json() {
+        let worker = TypedWorker::new(handler);
+        assert_eq!(
+            Disposition::Ack,
+            worker.decode_and_handle(message(Some(r#"{"text": "Hello"}"#)))
+        );
+    }
+
+    #[test]
+    fn test_handler_error() {
+        let worker = TypedWorker::new(handler);
+        assert_eq!(
+            Disposition::Requeue,
+            worker.decode_and_handle(message(Some(r#"{"text": "Bye"}"#)))
+        );
+    }
+
+    #[test]
+    fn test_invalid_body_is_dead_lettered() {
+        let worker = TypedWorker::new(handler);
+        match worker.decode_and_handle(message(Some("not json"))) {
+            Disposition::DeadLetter(_) => (),
+            other => panic!("Unexpected disposition: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_body_uses_decode_error() {
+        let worker = TypedWorker::new(handler).with_decode_error(|_| Disposition::Leave);
+        assert_eq!(Disposition::Leave, worker.decode_and_handle(message(None)));
+    }
+}
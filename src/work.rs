@@ -1,12 +1,227 @@
+use crate::context::MessageContext;
+use crate::disposition::Disposition;
 use crate::errors::WorkError;
+use futures::future::{self, BoxFuture, FutureExt};
 use rusoto_sqs::Message as SqsMessage;
+use std::future::Future;
+use std::time::Duration;
 
 /// Trait to implement to create your own Worker implementation
 pub trait Worker {
     /// Processes the specified sqs message
     ///
-    /// A WorkError can be returned on failture of message
-    /// If the error is unrecoverable a WorkError::UnrecoverableError should be returned
-    /// If the message should be requeued for later a WorkError::RecoverableError can be returned
-    fn process(&self, message: SqsMessage) -> Result<(), WorkError>;
+    /// The returned Disposition determines what happens to the message:
+    /// Disposition::Ack deletes it, Disposition::Retry makes it visible again after a delay,
+    /// Disposition::DeadLetter moves it to the dead letter queue, etc.
+    /// A Result<(), WorkError> can be converted into a Disposition with into()
+    ///
+    /// The MessageContext exposes the receive count, the source queue, a handle to
+    /// extend the visibility of the message and a cancellation token.
+    fn process(&self, message: SqsMessage, context: MessageContext) -> Disposition;
+
+    /// Overrides the message timeout of the processor for this worker
+    ///
+    /// The worker is run on a blocking thread so the message can be abandoned when the timeout
+    /// elapses. A sync worker cannot be interrupted though, it keeps its thread until process
+    /// returns
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called once before the processor fetches the first message.
+    /// Returning an error stops the processor
+    fn on_start(&self) -> Result<(), WorkError> {
+        Ok(())
+    }
+
+    /// Called when the queue becomes empty
+    fn on_idle(&self) {}
+
+    /// Called once after the processor has stopped
+    fn on_shutdown(&self) {}
+}
+
+/// Trait to implement for workers that need to await I/O while processing a message
+///
+/// Every Worker is also an AsyncWorker, so the Processor can be handed either kind.
+pub trait AsyncWorker {
+    /// Returns a future that processes the specified sqs message
+    ///
+    /// The resolved Disposition is handled the same way as for Worker::process
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition>;
+
+    /// Overrides the message timeout of the processor for this worker
+    ///
+    /// When the timeout elapses the future is dropped and the message cancellation token is cancelled
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether process does its work on the calling thread before returning the future, as
+    /// a sync Worker does. Such workers are run on a blocking thread when the processor has to
+    /// keep a visibility heartbeat or timeout going while they work
+    fn is_blocking(&self) -> bool {
+        false
+    }
+
+    /// Called once before the processor fetches the first message.
+    /// Returning an error stops the processor
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// Called when the queue becomes empty
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+
+    /// Called once after the processor has stopped
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+}
+
+impl<T: Worker + ?Sized> AsyncWorker for T {
+    fn process(&self, message: SqsMessage, context: MessageContext) -> BoxFuture<'_, Disposition> {
+        future::ready(Worker::process(self, message, context)).boxed()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Worker::timeout(self)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        future::ready(Worker::on_start(self)).boxed()
+    }
+
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        future::ready(Worker::on_idle(self)).boxed()
+    }
+
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        future::ready(Worker::on_shutdown(self)).boxed()
+    }
+}
+
+/// Trait to implement for workers that want every message of a receive batch at once
+///
+/// Each message of a batch takes an in flight slot, so batches hold at most max_in_flight
+/// messages.
+pub trait BatchWorker {
+    /// Returns a future that processes all of the messages received in one fetch
+    ///
+    /// The future must resolve to one Disposition per message, in the same order as the messages.
+    /// Each Disposition is handled individually, so a batch can partially fail.
+    fn process_batch(&self, messages: Vec<SqsMessage>) -> BoxFuture<'_, Vec<Disposition>>;
+
+    /// Called once before the processor fetches the first batch.
+    /// Returning an error stops the processor
+    fn on_start(&self) -> BoxFuture<'_, Result<(), WorkError>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// Called when the queue becomes empty
+    fn on_idle(&self) -> BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+
+    /// Called once after the processor has stopped
+    fn on_shutdown(&self) -> BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+}
+
+/// Creates a Worker out of a function or closure
+///
+/// let worker = worker_fn(|message: SqsMessage| {
+///     println!("Received message: {:?}", message);
+///     Disposition::Ack
+/// });
+pub fn worker_fn<F, R>(f: F) -> FnWorker<F>
+where
+    F: Fn(SqsMessage) -> R,
+    R: Into<Disposition>,
+{
+    FnWorker { f }
+}
+
+/// Creates an AsyncWorker out of a function or closure that returns a future
+///
+/// let worker = async_worker_fn(|message: SqsMessage| async move {
+///     save(message).await;
+///     Disposition::Ack
+/// });
+pub fn async_worker_fn<F, Fut, R>(f: F) -> AsyncFnWorker<F>
+where
+    F: Fn(SqsMessage) -> Fut,
+    Fut: Future<Output = R> + Send + 'static,
+    R: Into<Disposition>,
+{
+    AsyncFnWorker { f }
+}
+
+/// Worker created with worker_fn
+#[derive(Clone)]
+pub struct FnWorker<F> {
+    f: F,
+}
+
+impl<F, R> Worker for FnWorker<F>
+where
+    F: Fn(SqsMessage) -> R,
+    R: Into<Disposition>,
+{
+    fn process(&self, message: SqsMessage, _context: MessageContext) -> Disposition {
+        (self.f)(message).into()
+    }
+}
+
+/// AsyncWorker created with async_worker_fn
+#[derive(Clone)]
+pub struct AsyncFnWorker<F> {
+    f: F,
+}
+
+impl<F, Fut, R> AsyncWorker for AsyncFnWorker<F>
+where
+    F: Fn(SqsMessage) -> Fut,
+    Fut: Future<Output = R> + Send + 'static,
+    R: Into<Disposition>,
+{
+    fn process(&self, message: SqsMessage, _context: MessageContext) -> BoxFuture<'_, Disposition> {
+        (self.f)(message).map(|result| result.into()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::process_for_test;
+
+    #[test]
+    fn test_worker_fn() {
+        let worker = worker_fn(|message: SqsMessage| {
+            message
+                .body
+                .ok_or(WorkError::UnRecoverableError("No body".to_owned()))
+                .map(|_| ())
+        });
+        assert_eq!(
+            Disposition::DeadLetter("No body".to_owned()),
+            process_for_test(&worker, SqsMessage::default())
+        );
+    }
+
+    #[test]
+    fn test_async_worker_fn() {
+        let worker = async_worker_fn(|_message: SqsMessage| async { Disposition::Release });
+        assert_eq!(
+            Disposition::Release,
+            process_for_test(&worker, SqsMessage::default())
+        );
+    }
 }
@@ -3,7 +3,10 @@ extern crate serde_derive;
 
 mod processor_util;
 
-use crate::processor_util::{with_processor_util, Action, Payload};
+use crate::processor_util::{with_processor_util, with_running_processor, Action, Payload};
+use rs_queue_processor::config::{Config, ReceiveSettings};
+use rs_queue_processor::prefetch::PrefetchConfig;
+use std::thread;
 use std::time::Duration;
 
 macro_rules! assert_received {
@@ -48,3 +51,39 @@ fn test_fail_requeue() {
         assert_received!(pu, Duration::from_secs(20), payload);
     });
 }
+
+#[test]
+fn test_shutdown_releases_unstarted_messages() {
+    let configure = |config: Config| {
+        let receive = ReceiveSettings::short_polling(Duration::from_millis(100))
+            .with_visibility_timeout(Duration::from_secs(30));
+        config
+            .with_receive(receive)
+            .with_prefetch(PrefetchConfig::new(5))
+            .with_shutdown_timeout(Duration::from_secs(1))
+    };
+    with_running_processor(configure, |pu, processor| {
+        let in_flight = Payload::new(
+            "This message should still be processing at shutdown",
+            Action::Sleep(3000),
+        );
+        pu.send_payload(in_flight.clone());
+        assert_received!(pu, Duration::from_secs(5), in_flight);
+
+        let unstarted = Payload::new(
+            "This message should wait for a slot and be released at shutdown",
+            Action::Success,
+        );
+        pu.send_payload(unstarted.clone());
+        // give the processor time to receive the message into the prefetch buffer
+        thread::sleep(Duration::from_secs(1));
+
+        processor.shutdown();
+        match processor.wait_for_stop(Duration::from_secs(5)) {
+            Ok(result) => assert!(result.is_ok()),
+            Err(_) => panic!("process() did not return after shutdown"),
+        }
+        // the in flight message stays hidden, the unstarted one is visible again right away
+        assert_eq!(Some(unstarted), pu.receive_payload(Duration::from_secs(2)));
+    });
+}
@@ -1,13 +1,21 @@
 use rs_queue_processor::config::{Config, Mode};
+use rs_queue_processor::errors::ProcessorError;
+use rs_queue_processor::handle::ProcessorHandle;
 use rs_queue_processor::processor::Processor;
 use rusoto_core::Region;
-use rusoto_sqs::{CreateQueueRequest, SendMessageRequest, Sqs, SqsClient as RusotoSqsClient};
+use rusoto_sqs::{
+    CreateQueueRequest, ReceiveMessageRequest, SendMessageRequest, Sqs,
+    SqsClient as RusotoSqsClient,
+};
 use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
 use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
 use testcontainers::images::elasticmq::ElasticMQ;
 use testcontainers::{clients, Docker};
 
+use rs_queue_processor::context::MessageContext;
+use rs_queue_processor::disposition::Disposition;
 use rs_queue_processor::errors::WorkError;
 use rs_queue_processor::work::Worker;
 use rusoto_sqs::Message;
@@ -18,6 +26,8 @@ pub enum Action {
     Success,
     FailRequeue,
     FailDelete,
+    /// Blocks the worker for the number of milliseconds before succeeding
+    Sleep(u64),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -47,7 +57,7 @@ impl TestWorker {
 }
 
 impl Worker for TestWorker {
-    fn process(&self, message: Message) -> Result<(), WorkError> {
+    fn process(&self, message: Message, _context: MessageContext) -> Disposition {
         println!("Worker process called!");
         let sender = mpsc::SyncSender::clone(&self.sender);
         message
@@ -71,8 +81,13 @@ impl Worker for TestWorker {
                     Action::FailDelete => Err(WorkError::UnRecoverableError(
                         "Received delete action".to_owned(),
                     )),
+                    Action::Sleep(millis) => {
+                        thread::sleep(Duration::from_millis(millis));
+                        Ok(())
+                    }
                 }
             })
+            .into()
     }
 }
 
@@ -83,6 +98,59 @@ where
     let (tx, rx) = mpsc::sync_channel::<Payload>(1);
     println!("Creating Channel");
 
+    with_queue(|region, sqs_client, queue_url| {
+        let config = Config::default().with_mode(Mode::AWS(region, queue_url.to_owned()));
+        let worker = TestWorker::new(tx);
+
+        tokio::spawn(async move {
+            let processor = Processor::new(&config, Box::new(worker)).unwrap();
+            if let Err(e) = processor.process().await {
+                eprintln!("Processor stopped with error: {}", e);
+            }
+        });
+
+        f(ProcessorUtil::new(
+            rx,
+            Arc::clone(&sqs_client),
+            queue_url.clone(),
+        ));
+    });
+}
+
+/// Runs a processor configured by configure on its own runtime, so the test can shut it down
+/// and wait for process() to return
+pub fn with_running_processor<C, F>(configure: C, f: F)
+where
+    C: FnOnce(Config) -> Config,
+    F: FnOnce(ProcessorUtil, RunningProcessor) -> (),
+{
+    let (tx, rx) = mpsc::sync_channel::<Payload>(10);
+
+    with_queue(|region, sqs_client, queue_url| {
+        let config =
+            configure(Config::default().with_mode(Mode::AWS(region, queue_url.to_owned())));
+        let processor = Processor::new(&config, Box::new(TestWorker::new(tx))).unwrap();
+        let handle = processor.handle();
+        let (stopped_tx, stopped) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            let result = runtime.block_on(processor.process());
+            // the test may have given up waiting already
+            let _ = stopped_tx.send(result);
+        });
+
+        f(
+            ProcessorUtil::new(rx, Arc::clone(&sqs_client), queue_url.clone()),
+            RunningProcessor { handle, stopped },
+        );
+    });
+}
+
+fn with_queue<F>(f: F)
+where
+    F: FnOnce(Region, Arc<RusotoSqsClient>, String) -> (),
+{
     let queue_name = "test-queue";
     let docker = clients::Cli::default();
     let node = docker.run(ElasticMQ::default());
@@ -93,21 +161,28 @@ where
     println!("Creating queue");
 
     let queue_url = create_queue(Arc::clone(&sqs_client), queue_name.to_owned()).unwrap();
-    let config = Config::default().with_mode(Mode::AWS(region, queue_url.to_owned()));
 
     println!("Queue successfully created: {:?}", &queue_url);
-    let worker = TestWorker::new(tx);
+    f(region, sqs_client, queue_url);
+}
 
-    tokio::spawn(async move {
-        let mut processor = Processor::new(&config, Box::new(worker)).unwrap();
-        processor.process().await;
-    });
+pub struct RunningProcessor {
+    handle: ProcessorHandle,
+    stopped: Receiver<Result<(), ProcessorError>>,
+}
+
+impl RunningProcessor {
+    pub fn shutdown(&self) {
+        self.handle.shutdown()
+    }
 
-    f(ProcessorUtil::new(
-        rx,
-        Arc::clone(&sqs_client),
-        queue_url.clone(),
-    ));
+    /// Waits for process() to return and returns its result
+    pub fn wait_for_stop(
+        &self,
+        duration: Duration,
+    ) -> Result<Result<(), ProcessorError>, RecvTimeoutError> {
+        self.stopped.recv_timeout(duration)
+    }
 }
 
 pub struct ProcessorUtil {
@@ -137,6 +212,22 @@ impl ProcessorUtil {
     pub fn wait_for_payload(&self, duration: Duration) -> Result<Payload, RecvTimeoutError> {
         self.rx.recv_deadline(Instant::now() + duration)
     }
+
+    /// Receives a payload from the queue directly, bypassing the processor
+    pub fn receive_payload(&self, wait_time: Duration) -> Option<Payload> {
+        let mut request = ReceiveMessageRequest::default();
+        request.queue_url = self.queue_url.clone();
+        request.wait_time_seconds = Some(wait_time.as_secs() as i64);
+        self.sqs_client
+            .receive_message(request)
+            .sync()
+            .map_err(|e| panic!("Could not receive message {:?}", e))
+            .ok()
+            .and_then(|result| result.messages)
+            .and_then(|messages| messages.into_iter().next())
+            .and_then(|message| message.body)
+            .and_then(|body| serde_json::from_str(&body).ok())
+    }
 }
 
 fn send_message(